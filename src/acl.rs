@@ -0,0 +1,245 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Access control for the mapped attributes and rows.
+//!
+//! The first `[[acl]]` rule matching the bound identity applies. Without any rules
+//! everything is accessible, with rules an identity no rule matches can not access anything.
+
+use std::collections::HashSet;
+
+use crate::config::{Config, ConfigAcl};
+
+/// Who a connection is bound as.
+pub enum Identity<'a> {
+    Anonymous,
+    /// A service account from `[[ldap.accounts]]`
+    Account(&'a str),
+    /// A row of the table
+    User(&'a str),
+}
+
+/// The access rights of an identity.
+pub struct Access {
+    read: Attributes,
+    search: Attributes,
+    compare: Attributes,
+    /// SQL predicate the visible rows have to match
+    pub rows: Option<String>,
+}
+
+enum Attributes {
+    All,
+    /// Lowercase attribute names
+    Only(HashSet<String>),
+}
+
+impl Access {
+    pub fn new(conf: &Config, identity: Identity) -> Self {
+        if conf.acl.is_empty() {
+            return Self {
+                read: Attributes::All,
+                search: Attributes::All,
+                compare: Attributes::All,
+                rows: None,
+            };
+        }
+        match conf
+            .acl
+            .iter()
+            .find(|rule| rule.who.iter().any(|who| matches(who, &identity)))
+        {
+            Some(rule) => Self {
+                read: Attributes::new(&rule.read),
+                search: Attributes::new(&rule.search),
+                compare: Attributes::new(&rule.compare),
+                rows: rule.rows.clone(),
+            },
+            None => Self {
+                read: Attributes::Only(HashSet::new()),
+                search: Attributes::Only(HashSet::new()),
+                compare: Attributes::Only(HashSet::new()),
+                // Hide the rows as well, their DNs would be visible otherwise
+                rows: Some("1 = 0".to_owned()),
+            },
+        }
+    }
+
+    pub fn can_read(&self, attr: &str) -> bool {
+        self.read.contains(attr)
+    }
+
+    pub fn can_search(&self, attr: &str) -> bool {
+        self.search.contains(attr)
+    }
+
+    pub fn can_compare(&self, attr: &str) -> bool {
+        self.compare.contains(attr)
+    }
+}
+
+impl Attributes {
+    fn new(names: &[String]) -> Self {
+        if names.iter().any(|name| name == "*") {
+            Attributes::All
+        } else {
+            Attributes::Only(names.iter().map(|name| name.to_ascii_lowercase()).collect())
+        }
+    }
+
+    fn contains(&self, attr: &str) -> bool {
+        match self {
+            Attributes::All => true,
+            Attributes::Only(names) => names.contains(&attr.to_ascii_lowercase()),
+        }
+    }
+}
+
+/// `*`, `anonymous`, `accounts`, `users` or a DN.
+fn matches(who: &str, identity: &Identity) -> bool {
+    match (who, identity) {
+        ("*", _) | ("anonymous", Identity::Anonymous) => true,
+        ("accounts", Identity::Account(_)) | ("users", Identity::User(_)) => true,
+        (dn, Identity::Account(bound) | Identity::User(bound)) => dn.eq_ignore_ascii_case(bound),
+        _ => false,
+    }
+}
+
+/// Makes sure the rules only name mapped attributes.
+pub fn check(conf: &Config) -> Result<(), String> {
+    for ConfigAcl {
+        who,
+        read,
+        search,
+        compare,
+        ..
+    } in &conf.acl
+    {
+        if who.is_empty() {
+            return Err("acl rules need at least one who".to_owned());
+        }
+        for attr in read.iter().chain(search).chain(compare) {
+            if attr != "*" && conf.mappings.get(attr).is_none() {
+                return Err(format!("The acl attribute {} is not mapped", attr));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_backend::restrict_rows;
+    use crate::sql_query::{Column, Condition, PostgreSQL, Select};
+
+    const CONFIG: &str = r#"
+        [server]
+        [sql]
+        backend = "PostgreSQL"
+        database = "directory"
+        table = "users"
+        [ldap]
+        suffix = "ou=People,dc=example,dc=com"
+        [mappings]
+        cn = "uid"
+        mail = "email"
+
+        [[acl]]
+        who = ["cn=pbx,dc=example,dc=com"]
+        read = ["*"]
+        rows = "phone IS NOT NULL"
+
+        [[acl]]
+        who = ["anonymous"]
+        read = ["cn"]
+        rows = "public"
+    "#;
+
+    fn config() -> Config {
+        toml::from_str(CONFIG).unwrap()
+    }
+
+    /// The query for the entries matching `uid = 'alice'` the identity may see.
+    fn render(access: &Access) -> String {
+        Select {
+            columns: vec![(Column::Expr("uid".to_owned()), "cn".to_owned())],
+            table: "users".to_owned(),
+            filter: Some(restrict_rows(
+                Condition::EqualsIgnoreCase("uid".to_owned(), "alice".to_owned()),
+                access,
+            )),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        }
+        .render(&PostgreSQL)
+        .0
+    }
+
+    #[test]
+    fn rows_granted() {
+        let conf = config();
+        let access = Access::new(&conf, Identity::Account("CN=PBX,dc=example,dc=com"));
+        assert_eq!(access.rows.as_deref(), Some("phone IS NOT NULL"));
+        assert!(access.can_read("mail"));
+        assert_eq!(
+            render(&access),
+            "SELECT uid AS \"cn\" FROM users \
+             WHERE (LOWER(uid) = LOWER($1) AND (phone IS NOT NULL))"
+        );
+    }
+
+    #[test]
+    fn rows_denied() {
+        // No rule matches, nothing is visible
+        let conf = config();
+        let access = Access::new(
+            &conf,
+            Identity::User("cn=alice,ou=People,dc=example,dc=com"),
+        );
+        assert_eq!(access.rows.as_deref(), Some("1 = 0"));
+        assert!(!access.can_read("cn"));
+        assert!(!access.can_search("cn"));
+        assert_eq!(
+            render(&access),
+            "SELECT uid AS \"cn\" FROM users WHERE (LOWER(uid) = LOWER($1) AND (1 = 0))"
+        );
+    }
+
+    #[test]
+    fn rows_anonymous() {
+        let conf = config();
+        let access = Access::new(&conf, Identity::Anonymous);
+        assert_eq!(access.rows.as_deref(), Some("public"));
+        assert!(access.can_read("cn"));
+        assert!(!access.can_read("mail"));
+        assert_eq!(
+            render(&access),
+            "SELECT uid AS \"cn\" FROM users WHERE (LOWER(uid) = LOWER($1) AND (public))"
+        );
+    }
+
+    #[test]
+    fn rows_without_rules() {
+        let mut conf = config();
+        conf.acl.clear();
+        let access = Access::new(&conf, Identity::Anonymous);
+        assert_eq!(access.rows, None);
+        assert_eq!(
+            render(&access),
+            "SELECT uid AS \"cn\" FROM users WHERE LOWER(uid) = LOWER($1)"
+        );
+    }
+}
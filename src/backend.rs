@@ -0,0 +1,205 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use ldap3_proto::proto::{LdapFilter, LdapResultCode};
+use sqlx::error::DatabaseError;
+
+use crate::acl::Access;
+
+/// A source of directory entries the LDAP searches are answered from.
+pub trait Backend: Send + Sync {
+    /// Streams the entries matching the search.
+    fn search<'a>(&'a self, search: &'a Search<'a>) -> BoxStream<'a, Result<Entry, BackendError>>;
+
+    /// Counts the entries matching the search, ignoring its size limit and offset.
+    /// With `before`, only the entries sorting before the value by the first sort key count.
+    fn count<'a>(
+        &'a self,
+        search: &'a Search<'a>,
+        before: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u32, BackendError>>;
+
+    /// Looks up the password hash of the entry with the cn, if it has one.
+    fn credentials<'a>(
+        &'a self,
+        cn: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Credentials>, BackendError>>;
+
+    /// Checks whether the attribute of the entry has the value, `None` if there is no such entry.
+    fn compare<'a>(
+        &'a self,
+        compare: &'a Compare<'a>,
+    ) -> BoxFuture<'a, Result<Option<bool>, BackendError>>;
+}
+
+/// An LDAP search translated to the mapped attributes.
+pub struct Search<'a> {
+    /// Lowercase names of the mapped attributes to return
+    pub attributes: Vec<&'a str>,
+    pub target: SearchTarget<'a>,
+    /// Maximum number of entries, 0 for no limit
+    pub size_limit: i32,
+    /// Mapped attributes to order the entries by
+    pub sort: Vec<Sort<'a>>,
+    /// Entries to skip when paging, the entries are ordered by cn after the sort keys
+    /// then so pages do not overlap
+    pub offset: Option<u32>,
+    /// Rights of the bound identity
+    pub access: &'a Access,
+}
+
+pub struct Sort<'a> {
+    /// Lowercase name of the mapped attribute
+    pub attribute: &'a str,
+    pub reverse: bool,
+}
+
+pub enum SearchTarget<'a> {
+    /// A single entry identified by its cn
+    Cn(&'a str),
+    /// All entries below the suffix matching the filter
+    Filter(&'a LdapFilter),
+}
+
+/// An LDAP compare translated to a mapped attribute.
+pub struct Compare<'a> {
+    pub cn: &'a str,
+    /// Lowercase name of the mapped attribute
+    pub attribute: &'a str,
+    pub value: &'a str,
+    /// Rights of the bound identity
+    pub access: &'a Access,
+}
+
+pub struct Entry {
+    pub cn: String,
+    /// Values in the order of `Search::attributes`
+    pub values: Vec<Option<String>>,
+}
+
+pub struct Credentials {
+    /// The cn as stored, which may differ in case from the requested one
+    pub cn: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    /// The database has not been connected yet
+    Connecting,
+    /// Connecting to the database failed, it is retried in the background
+    Unavailable,
+    Sql(sqlx::Error),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendError::Connecting => f.write_str("Connecting to the database"),
+            BackendError::Unavailable => f.write_str("The database is unavailable"),
+            BackendError::Sql(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl BackendError {
+    /// The LDAP result code and the diagnostic message for the client,
+    /// which does not reveal details about the database.
+    pub fn ldap_result(&self) -> (LdapResultCode, &'static str) {
+        match self {
+            BackendError::Connecting => (LdapResultCode::Busy, "Connecting to the database"),
+            BackendError::Unavailable => {
+                (LdapResultCode::Unavailable, "The database is unavailable")
+            }
+            BackendError::Sql(err) => match err {
+                sqlx::Error::PoolTimedOut => {
+                    (LdapResultCode::Busy, "No database connection available")
+                }
+                sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::Protocol(_)
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::WorkerCrashed => {
+                    (LdapResultCode::Unavailable, "The database is unavailable")
+                }
+                sqlx::Error::Database(err) => database_result(err.as_ref()),
+                sqlx::Error::ColumnDecode { .. }
+                | sqlx::Error::Decode(_)
+                | sqlx::Error::ColumnNotFound(_)
+                | sqlx::Error::TypeNotFound { .. } => (
+                    LdapResultCode::Other,
+                    "Could not decode the database result",
+                ),
+                _ => (LdapResultCode::Other, "Database error"),
+            },
+        }
+    }
+}
+
+/// Classifies errors reported by the database server.
+fn database_result(err: &dyn DatabaseError) -> (LdapResultCode, &'static str) {
+    let busy = (LdapResultCode::Busy, "The database is busy");
+    let unavailable = (LdapResultCode::Unavailable, "The database is unavailable");
+    let timeout = (
+        LdapResultCode::TimeLimitExceeded,
+        "The database query timed out",
+    );
+    let other = (LdapResultCode::Other, "Database error");
+
+    if let Some(err) = err.try_downcast_ref::<sqlx::postgres::PgDatabaseError>() {
+        match err.code() {
+            // query_canceled, raised by statement_timeout
+            "57014" => timeout,
+            // Connection exceptions, shutdown or the server is starting up
+            code if code.starts_with("08") || code.starts_with("57P") => unavailable,
+            // Insufficient resources, lock_not_available, serialization_failure, deadlock_detected
+            code if code.starts_with("53") || code == "55P03" || code.starts_with("40") => busy,
+            _ => other,
+        }
+    } else if let Some(err) = err.try_downcast_ref::<sqlx::mysql::MySqlDatabaseError>() {
+        match err.number() {
+            // ER_QUERY_TIMEOUT (MySQL), ER_STATEMENT_TIMEOUT (MariaDB)
+            3024 | 1969 => timeout,
+            // ER_SERVER_SHUTDOWN
+            1053 => unavailable,
+            // ER_CON_COUNT_ERROR, ER_TOO_MANY_USER_CONNECTIONS, ER_LOCK_WAIT_TIMEOUT, ER_LOCK_DEADLOCK
+            1040 | 1203 | 1205 | 1213 => busy,
+            _ => other,
+        }
+    } else if err
+        .try_downcast_ref::<sqlx::sqlite::SqliteError>()
+        .is_some()
+    {
+        // The primary result code is the lower byte of the extended one
+        match err.code().and_then(|code| code.parse::<i32>().ok()) {
+            // SQLITE_BUSY, SQLITE_LOCKED
+            Some(code) if matches!(code & 0xff, 5 | 6) => busy,
+            // SQLITE_CANTOPEN
+            Some(code) if code & 0xff == 14 => unavailable,
+            _ => other,
+        }
+    } else {
+        other
+    }
+}
+
+impl From<sqlx::Error> for BackendError {
+    fn from(err: sqlx::Error) -> Self {
+        BackendError::Sql(err)
+    }
+}
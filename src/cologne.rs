@@ -0,0 +1,282 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Kölner Phonetik (cologne phonetics), a soundex like code for German names.
+//!
+//! The assertion value is encoded here, the column values by an SQL expression of nested
+//! `REPLACE()` calls for PostgreSQL and MySQL and by a function calling `encode` for SQLite.
+//! The expression ignores digits, spaces and the punctuation in `IGNORED`, values with
+//! other characters that are not letters or umlauts get codes the assertion values never have.
+
+use crate::sql_query::Dialect;
+
+const IGNORED: [&str; 7] = [" ", "-", ".", ",", "'", "/", "&"];
+
+/// Codes the letters of the value, e.g. `Meier`, `Meyer` and `Maier` are all `67`.
+pub fn encode(value: &str) -> String {
+    let letters: Vec<char> = value
+        .chars()
+        .filter_map(|c| match c {
+            'ä' | 'Ä' => Some('A'),
+            'ö' | 'Ö' => Some('O'),
+            'ü' | 'Ü' => Some('U'),
+            'ß' => Some('S'),
+            c if c.is_ascii_alphabetic() => Some(c.to_ascii_uppercase()),
+            _ => None,
+        })
+        .collect();
+
+    let mut codes = String::with_capacity(letters.len() * 2);
+    for (i, &letter) in letters.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|i| letters[i]);
+        let next = letters.get(i + 1).copied();
+        let next_in = |set: &str| next.is_some_and(|c| set.contains(c));
+        codes.push_str(match letter {
+            'A' | 'E' | 'I' | 'J' | 'O' | 'U' | 'Y' => "0",
+            'H' => "",
+            'B' => "1",
+            'P' if next == Some('H') => "3",
+            'P' => "1",
+            'D' | 'T' if next_in("CSZ") => "8",
+            'D' | 'T' => "2",
+            'F' | 'V' | 'W' => "3",
+            'G' | 'K' | 'Q' => "4",
+            'C' if i == 0 && next_in("AHKLOQRUX") => "4",
+            'C' if i == 0 => "8",
+            'C' if prev.is_some_and(|c| c == 'S' || c == 'Z') => "8",
+            'C' if next_in("AHKOQUX") => "4",
+            'C' => "8",
+            'X' if prev.is_some_and(|c| "CKQ".contains(c)) => "8",
+            'X' => "48",
+            'L' => "5",
+            'M' | 'N' => "6",
+            'R' => "7",
+            _ => "8",
+        });
+    }
+
+    let mut code = String::with_capacity(codes.len());
+    let mut last = None;
+    for c in codes.chars() {
+        // Repeated codes are collapsed before the vowels are removed
+        if last != Some(c) && (c != '0' || code.is_empty()) {
+            code.push(c);
+        }
+        last = Some(c);
+    }
+    code
+}
+
+/// SQL expression with the code of the text column expression.
+pub fn sql_expression<D: Dialect + ?Sized>(dialect: &D, expr: &str) -> String {
+    let mut replacements: Vec<(String, String)> = Vec::new();
+    let mut add = |from: &str, to: &str| replacements.push((from.to_owned(), to.to_owned()));
+
+    // The letters keep their case until they are coded, so lowercase ones mark decisions.
+    // Rules depending on the next letter replace pairs, as REPLACE() does not overlap
+    // matches the previous letter of a pair is unaffected.
+    for prev in ["C", "K", "Q"] {
+        add(&format!("{}X", prev), &format!("{}x", prev));
+    }
+    for letter in ["D", "T"] {
+        for next in ["C", "S", "Z"] {
+            add(&format!("{}{}", letter, next), &format!("8{}", next));
+        }
+    }
+    add("SC", "S8");
+    add("ZC", "Z8");
+    // The value is prefixed with ^ for the rules at its start
+    for next in ["A", "H", "K", "L", "O", "Q", "R", "U", "X", "x"] {
+        add(&format!("^C{}", next), &format!("^4{}", next));
+    }
+    add("^C", "^8");
+    for next in ["A", "H", "K", "O", "Q", "U", "X", "x"] {
+        add(&format!("C{}", next), &format!("4{}", next));
+    }
+    add("C", "8");
+    add("PH", "3H");
+    for (letters, code) in [
+        ("AEIJOUY", "0"),
+        ("H", ""),
+        ("BP", "1"),
+        ("DT", "2"),
+        ("FVW", "3"),
+        ("GKQ", "4"),
+        ("X", "48"),
+        ("x", "8"),
+        ("L", "5"),
+        ("MN", "6"),
+        ("R", "7"),
+        ("SZ", "8"),
+    ] {
+        for letter in letters.chars() {
+            add(&letter.to_string(), code);
+        }
+    }
+    // Each pass halves runs of the same code, runs of up to 32 are collapsed
+    for _ in 0..5 {
+        for code in '0'..='8' {
+            add(&format!("{}{}", code, code), &code.to_string());
+        }
+    }
+    // o marks a vowel at the start, which is kept
+    add("^0", "o");
+    add("0", "");
+    add("o", "0");
+    add("^", "");
+
+    // Digits are removed before they could be confused with codes
+    let mut sql = expr.to_owned();
+    for (from, to) in [
+        ("ä", "A"),
+        ("ö", "O"),
+        ("ü", "U"),
+        ("Ä", "A"),
+        ("Ö", "O"),
+        ("Ü", "U"),
+        ("ß", "S"),
+    ] {
+        sql = replace(&sql, from, to);
+    }
+    sql = format!("UPPER({})", sql);
+    for ignored in IGNORED
+        .iter()
+        .copied()
+        .chain(["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])
+    {
+        sql = replace(&sql, ignored, "");
+    }
+    sql = dialect.concat("'^'", &sql);
+    for (from, to) in &replacements {
+        sql = replace(&sql, from, to);
+    }
+    sql
+}
+
+fn replace(expr: &str, from: &str, to: &str) -> String {
+    // None of the strings contain quotes except the ignored apostrophe
+    format!(
+        "REPLACE({}, '{}', '{}')",
+        expr,
+        from.replace('\'', "''"),
+        to
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Connection, SqliteConnection};
+
+    use super::*;
+    use crate::sql_query::SQLite;
+
+    #[test]
+    fn known_answers() {
+        for (value, code) in [
+            ("Müller", "657"),
+            ("Christoph", "47823"),
+            ("Dieter Schulz", "227858"),
+            ("Ocean", "086"),
+            ("Meier", "67"),
+            ("Meyer", "67"),
+            ("Maier", "67"),
+            ("Müller-Lüdenscheidt", "65752682"),
+            ("Breschnew", "17863"),
+            ("", ""),
+        ] {
+            assert_eq!(encode(value), code, "{}", value);
+        }
+    }
+
+    /// Runs the expression on an in-memory SQLite database. Its parser can not nest that
+    /// many calls, which is why it uses a function instead, so the calls are run one by one.
+    async fn sql_encode(db: &mut SqliteConnection, value: &str) -> String {
+        let sql = sql_expression(&SQLite, "?");
+        let mut expr = sql.as_str();
+        let mut calls = Vec::new();
+        while expr != "?" {
+            if let Some(args) = expr
+                .strip_prefix("REPLACE(")
+                .and_then(|expr| expr.strip_suffix("')"))
+            {
+                // The replacement never contains quotes, the searched string only doubled ones
+                let (args, to) = args.rsplit_once('\'').unwrap();
+                let args = args.strip_suffix("', ").unwrap();
+                let mut start = args.len();
+                while let Some(quote) = args[..start].rfind('\'') {
+                    start = quote;
+                    if !args[..quote].ends_with('\'') {
+                        break;
+                    }
+                    start -= 1;
+                }
+                let from = args[start + 1..].replace("''", "'");
+                calls.push(("SELECT REPLACE(?, ?, ?)", Some((from, to.to_owned()))));
+                expr = args[..start].strip_suffix(", ").unwrap();
+            } else if let Some(arg) = expr.strip_prefix("('^' || ") {
+                calls.push(("SELECT '^' || ?", None));
+                expr = arg.strip_suffix(')').unwrap();
+            } else {
+                let arg = expr.strip_prefix("UPPER(").unwrap();
+                calls.push(("SELECT UPPER(?)", None));
+                expr = arg.strip_suffix(')').unwrap();
+            }
+        }
+
+        let mut code = value.to_owned();
+        for (query, args) in calls.into_iter().rev() {
+            let mut query = sqlx::query_scalar(query).bind(code);
+            if let Some((from, to)) = args {
+                query = query.bind(from).bind(to);
+            }
+            code = query.fetch_one(&mut *db).await.unwrap();
+        }
+        code
+    }
+
+    #[tokio::test]
+    async fn sql_expression_matches_encode() {
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        for value in [
+            "Müller",
+            "Christoph",
+            "Dieter Schulz",
+            "Ocean",
+            "Meyer",
+            "Müller-Lüdenscheidt",
+            "Breschnew",
+            "Wikipedia",
+            "Xaver",
+            "Acker",
+            "Sachs",
+            "Cäsar",
+            "Chemnitz",
+            "Großmann",
+            "Philipp",
+            "Dietz",
+            "Axel",
+            "Heck",
+            "O'Brien",
+            "Lange 2",
+            "",
+        ] {
+            assert_eq!(sql_encode(&mut db, value).await, encode(value), "{}", value);
+        }
+
+        // Runs of more than 32 letters with the same code are not collapsed completely
+        let value = format!("Ma{}", "n".repeat(40));
+        assert_eq!(encode(&value), "66");
+        assert_eq!(sql_encode(&mut db, &value).await, "666");
+    }
+}
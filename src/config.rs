@@ -16,7 +16,9 @@ use std::collections::HashMap;
 use std::fmt;
 use std::iter::FusedIterator;
 use std::marker::PhantomData;
+use std::path::PathBuf;
 
+use ipnet::IpNet;
 use serde::de::{Deserialize, MapAccess, Visitor};
 use serde::Deserializer;
 use serde_derive::Deserialize;
@@ -27,6 +29,8 @@ pub struct Config {
     pub sql: ConfigSql,
     pub ldap: ConfigLdap,
     pub mappings: Mappings,
+    #[serde(default)]
+    pub acl: Vec<ConfigAcl>,
 }
 
 #[derive(Deserialize)]
@@ -41,6 +45,7 @@ pub struct ConfigServer {
     pub seccomp: bool,
     #[serde(default = "default_server_debug")]
     pub debug: bool,
+    pub tls: Option<ConfigServerTls>,
 }
 
 fn default_server_ip() -> std::net::IpAddr {
@@ -60,39 +65,400 @@ fn default_server_debug() -> bool {
     false
 }
 
+#[derive(Deserialize)]
+pub struct ConfigServerTls {
+    // PEM files, the certificate file may contain the chain
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    // LDAPS port
+    #[serde(default = "default_server_tls_port")]
+    pub port: u16,
+    // Refuse binds and searches on plain connections before StartTLS
+    #[serde(default)]
+    pub require: bool,
+}
+
+fn default_server_tls_port() -> u16 {
+    636
+}
+
 #[derive(Deserialize)]
 pub struct ConfigSql {
     pub backend: ConfigSqlBackend,
-    pub host: String,
+    // host, user and pass are not used by SQLite
+    // A single host or a list of hosts to fail over to, in order of preference
+    #[serde(default, deserialize_with = "deserialize_hosts")]
+    pub host: Vec<String>,
     pub port: Option<u16>,
+    #[serde(default)]
+    pub target_session_attrs: ConfigSqlTargetSessionAttrs,
+    #[serde(default)]
     pub user: String,
+    #[serde(default)]
     pub pass: String,
+    // Alternative sources of the password, only one may be set
+    pub pass_file: Option<PathBuf>,
+    pub pass_env: Option<String>,
+    // Name of a systemd credential in $CREDENTIALS_DIRECTORY
+    pub pass_credential: Option<String>,
+    // Path to the database file for SQLite
     pub database: String,
     pub table: String,
+    // Column expression with the password hashes simple binds are checked against,
+    // binds other than anonymous are refused when unset
+    pub password_column: Option<String>,
+    // Only used by SQLite
+    #[serde(default = "default_sql_read_only")]
+    pub read_only: bool,
+    // TLS, not used by SQLite
+    pub sslmode: Option<ConfigSqlSslMode>,
+    pub sslrootcert: Option<PathBuf>,
+    pub sslcert: Option<PathBuf>,
+    pub sslkey: Option<PathBuf>,
+    #[serde(default)]
+    pub pool: ConfigSqlPool,
+    // Algorithm of approximate (~=) filters
+    #[serde(default)]
+    pub approx: ConfigSqlApprox,
+}
+
+fn default_sql_read_only() -> bool {
+    true
+}
+
+fn deserialize_hosts<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hosts {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match Hosts::deserialize(deserializer)? {
+        Hosts::One(host) => vec![host],
+        Hosts::Many(hosts) => hosts,
+    })
 }
 
 impl ConfigSql {
-    pub fn socket(&self) -> Option<&str> {
-        if self.host.starts_with("unix://") {
-            Some(&self.host["unix://".len()..])
-        } else {
-            None
+    /// The configured hosts in order of preference.
+    pub fn hosts(&self) -> Vec<SqlHost<'_>> {
+        if self.host.is_empty() {
+            return vec![SqlHost::Tcp("localhost", self.port)];
         }
+        self.host
+            .iter()
+            .map(|host| SqlHost::parse(host, self.port))
+            .collect()
+    }
+
+    /// Reads the password from the configured source.
+    pub fn password(&self) -> Result<String, String> {
+        let sources = [
+            !self.pass.is_empty(),
+            self.pass_file.is_some(),
+            self.pass_env.is_some(),
+            self.pass_credential.is_some(),
+        ];
+        if sources.iter().filter(|&&set| set).count() > 1 {
+            return Err(
+                "Only one of pass, pass_file, pass_env and pass_credential can be set".to_owned(),
+            );
+        }
+
+        if let Some(var) = &self.pass_env {
+            return std::env::var(var)
+                .map_err(|err| format!("Can not read pass_env ${}: {}", var, err));
+        }
+        match self.password_file()? {
+            Some(path) => std::fs::read_to_string(&path)
+                .map(|pass| pass.trim_end_matches(['\r', '\n']).to_owned())
+                .map_err(|err| format!("Can not read password file {}: {}", path.display(), err)),
+            None => Ok(self.pass.clone()),
+        }
+    }
+
+    /// The file the password is read from, if any.
+    pub fn password_file(&self) -> Result<Option<PathBuf>, String> {
+        if let Some(name) = &self.pass_credential {
+            let dir = std::env::var_os("CREDENTIALS_DIRECTORY").ok_or_else(|| {
+                "pass_credential is set, but $CREDENTIALS_DIRECTORY is not".to_owned()
+            })?;
+            return Ok(Some(PathBuf::from(dir).join(name)));
+        }
+        Ok(self.pass_file.clone())
+    }
+
+    /// Makes sure the database provides the approximate matching algorithm.
+    pub fn check_approx(&self) -> Result<(), String> {
+        let supported = match self.approx {
+            ConfigSqlApprox::Equality | ConfigSqlApprox::Cologne => true,
+            ConfigSqlApprox::Soundex => self.backend != ConfigSqlBackend::SQLite,
+            ConfigSqlApprox::Trigram | ConfigSqlApprox::Dmetaphone => {
+                self.backend == ConfigSqlBackend::PostgreSQL
+            }
+        };
+        if !supported {
+            return Err(format!(
+                "approx = {} is not supported by {:?}",
+                self.approx, self.backend
+            ));
+        }
+        Ok(())
+    }
+
+    /// Makes sure the TLS files can be used before connecting.
+    pub fn check_tls(&self) -> Result<(), String> {
+        let tls_set = self.sslmode.is_some()
+            || self.sslrootcert.is_some()
+            || self.sslcert.is_some()
+            || self.sslkey.is_some();
+        if self.backend == ConfigSqlBackend::SQLite && tls_set {
+            return Err("The sql TLS options are not supported by SQLite".to_owned());
+        }
+        if self.sslcert.is_some() != self.sslkey.is_some() {
+            return Err("sslcert and sslkey have to be set together".to_owned());
+        }
+        for (key, path) in [
+            ("sslrootcert", &self.sslrootcert),
+            ("sslcert", &self.sslcert),
+            ("sslkey", &self.sslkey),
+        ] {
+            if let Some(path) = path {
+                std::fs::File::open(path)
+                    .map_err(|err| format!("Can not read {} {}: {}", key, path.display(), err))?;
+            }
+        }
+        Ok(())
     }
 }
 
-#[derive(Deserialize)]
+/// Connection pool tuning, unset values use the sqlx defaults.
+#[derive(Deserialize, Default)]
+pub struct ConfigSqlPool {
+    pub max_connections: Option<u32>,
+    pub min_connections: Option<u32>,
+    // Timeouts in seconds, 0 disables idle_timeout and max_lifetime
+    pub acquire_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub max_lifetime: Option<u64>,
+}
+
+impl ConfigSqlPool {
+    pub fn check(&self) -> Result<(), String> {
+        if self.max_connections == Some(0) {
+            return Err("sql.pool.max_connections has to be at least 1".to_owned());
+        }
+        // The sqlx default is 10 connections
+        let max = self.max_connections.unwrap_or(10);
+        if let Some(min) = self.min_connections {
+            if min > max {
+                return Err(format!(
+                    "sql.pool.min_connections ({}) is larger than max_connections ({})",
+                    min, max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SqlHost<'a> {
+    /// Host name or address and port
+    Tcp(&'a str, Option<u16>),
+    /// Path of a unix socket
+    Socket(&'a str),
+}
+
+impl<'a> SqlHost<'a> {
+    /// Parses `unix://<path>`, `<host>` or `<host>:<port>`, IPv6 addresses need brackets with a port.
+    fn parse(host: &'a str, default_port: Option<u16>) -> Self {
+        if let Some(path) = host.strip_prefix("unix://") {
+            return SqlHost::Socket(path);
+        }
+        let unbracket = |name: &'a str| name.trim_start_matches('[').trim_end_matches(']');
+        if let Some((name, port)) = host.rsplit_once(':') {
+            if !name.contains(':') || name.ends_with(']') {
+                if let Ok(port) = port.parse() {
+                    return SqlHost::Tcp(unbracket(name), Some(port));
+                }
+            }
+        }
+        SqlHost::Tcp(unbracket(host), default_port)
+    }
+}
+
+impl fmt::Display for SqlHost<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SqlHost::Tcp(name, None) => f.write_str(name),
+            SqlHost::Tcp(name, Some(port)) if name.contains(':') => {
+                write!(f, "[{}]:{}", name, port)
+            }
+            SqlHost::Tcp(name, Some(port)) => write!(f, "{}:{}", name, port),
+            SqlHost::Socket(path) => write!(f, "unix://{}", path),
+        }
+    }
+}
+
+/// Which kind of server to connect to, like the libpq parameter.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigSqlTargetSessionAttrs {
+    #[default]
+    Any,
+    ReadWrite,
+    Standby,
+}
+
+impl fmt::Display for ConfigSqlTargetSessionAttrs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSqlTargetSessionAttrs::Any => "any",
+            ConfigSqlTargetSessionAttrs::ReadWrite => "read-write",
+            ConfigSqlTargetSessionAttrs::Standby => "standby",
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ConfigSqlBackend {
     PostgreSQL,
+    MySQL,
+    SQLite,
+}
+
+/// How approximate filters match, the functions of pg_trgm and fuzzystrmatch are
+/// PostgreSQL extensions.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigSqlApprox {
+    // Like equality filters
+    #[default]
+    Equality,
+    // pg_trgm similarity
+    Trigram,
+    // fuzzystrmatch for PostgreSQL, built in for MySQL
+    Soundex,
+    // fuzzystrmatch
+    Dmetaphone,
+    // Kölner Phonetik
+    Cologne,
+}
+
+impl fmt::Display for ConfigSqlApprox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSqlApprox::Equality => "equality",
+            ConfigSqlApprox::Trigram => "trigram",
+            ConfigSqlApprox::Soundex => "soundex",
+            ConfigSqlApprox::Dmetaphone => "dmetaphone",
+            ConfigSqlApprox::Cologne => "cologne",
+        })
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigSqlSslMode {
+    Disable,
+    Prefer,
+    Require,
+    VerifyCa,
+    VerifyFull,
+}
+
+impl fmt::Display for ConfigSqlSslMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConfigSqlSslMode::Disable => "disable",
+            ConfigSqlSslMode::Prefer => "prefer",
+            ConfigSqlSslMode::Require => "require",
+            ConfigSqlSslMode::VerifyCa => "verify-ca",
+            ConfigSqlSslMode::VerifyFull => "verify-full",
+        })
+    }
 }
 
 #[derive(Deserialize)]
 pub struct ConfigLdap {
     pub suffix: String,
+    // Service accounts that are not rows of the table
+    #[serde(default)]
+    pub accounts: Vec<ConfigLdapAccount>,
+    // Allow anonymous binds and searches without a bind
+    #[serde(default = "default_ldap_anonymous")]
+    pub anonymous: bool,
+    // Searches need a bind first, an anonymous one suffices if allowed
+    #[serde(default)]
+    pub require_bind: bool,
+    // Networks anonymous access is allowed from, empty for all
+    #[serde(default)]
+    pub anonymous_networks: Vec<IpNet>,
+}
+
+fn default_ldap_anonymous() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct ConfigLdapAccount {
+    pub dn: String,
+    // Password hash in one of the formats supported for the password column
+    pub password: String,
+}
+
+impl ConfigLdap {
+    pub fn check(&self) -> Result<(), String> {
+        for (i, account) in self.accounts.iter().enumerate() {
+            if account.dn.is_empty() {
+                return Err("ldap.accounts need a dn".to_owned());
+            }
+            if self.accounts[..i]
+                .iter()
+                .any(|other| other.dn.eq_ignore_ascii_case(&account.dn))
+            {
+                return Err(format!("Duplicate ldap account {}", account.dn));
+            }
+            // Only the format matters, the result does not
+            crate::password::verify("", &account.password).map_err(|err| {
+                format!("Invalid password of ldap account {}: {}", account.dn, err)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConfigAcl {
+    // "*", "anonymous", "accounts", "users" or the DN of an account or a row
+    pub who: Vec<String>,
+    // Attributes that may be returned, used in filters and compared, "*" for all
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub search: Vec<String>,
+    #[serde(default)]
+    pub compare: Vec<String>,
+    // SQL predicate limiting the visible rows
+    pub rows: Option<String>,
 }
 
 pub struct Mappings {
-    mappings: HashMap<String, (String, String)>,
+    mappings: HashMap<String, (String, String, ConfigMappingType)>,
+}
+
+/// How the values of an attribute compare in greaterOrEqual and lessOrEqual filters.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConfigMappingType {
+    #[default]
+    Text,
+    Number,
+    Timestamp,
 }
 
 impl Mappings {
@@ -108,14 +474,22 @@ impl Mappings {
         }
     }
 
-    pub fn insert(&mut self, attr: String, col: String) {
-        self.mappings.insert(attr.to_ascii_lowercase(), (attr, col));
+    pub fn insert(&mut self, attr: String, col: String, value_type: ConfigMappingType) {
+        self.mappings
+            .insert(attr.to_ascii_lowercase(), (attr, col, value_type));
     }
 
     pub fn get(&self, attr: &str) -> Option<(&str, &str, &str)> {
         self.mappings
             .get_key_value(&attr.to_ascii_lowercase())
-            .map(|(attr_lower, (attr, col))| (attr_lower as &str, attr as &str, col as &str))
+            .map(|(attr_lower, (attr, col, _))| (attr_lower as &str, attr as &str, col as &str))
+    }
+
+    /// Unmapped attributes are text.
+    pub fn value_type(&self, attr: &str) -> ConfigMappingType {
+        self.mappings
+            .get(&attr.to_ascii_lowercase())
+            .map_or(ConfigMappingType::Text, |(_, _, value_type)| *value_type)
     }
 
     pub fn len(&self) -> usize {
@@ -139,7 +513,7 @@ impl<'a> IntoIterator for &'a Mappings {
 }
 
 pub struct MappingsIter<'a> {
-    iter: std::collections::hash_map::Iter<'a, String, (String, String)>,
+    iter: std::collections::hash_map::Iter<'a, String, (String, String, ConfigMappingType)>,
 }
 
 impl<'a> Iterator for MappingsIter<'a> {
@@ -148,7 +522,7 @@ impl<'a> Iterator for MappingsIter<'a> {
     fn next(&mut self) -> Option<Self::Item> {
         self.iter
             .next()
-            .map(|(attr_lower, (attr, col))| (attr_lower as &str, attr as &str, col as &str))
+            .map(|(attr_lower, (attr, col, _))| (attr_lower as &str, attr as &str, col as &str))
     }
 }
 
@@ -180,17 +554,34 @@ impl<'de> Visitor<'de> for MappingsVisitor {
     type Value = Mappings;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a map with ldap attributes as keys and sql column names as values")
+        formatter.write_str(
+            "a map with ldap attributes as keys and sql column names or tables with column and type as values",
+        )
     }
 
     fn visit_map<M>(self, mut access: M) -> Result<Self::Value, M::Error>
     where
         M: MapAccess<'de>,
     {
+        // Either just the column or { column = "...", type = "..." }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Mapping {
+            Column(String),
+            Typed {
+                column: String,
+                #[serde(rename = "type", default)]
+                value_type: ConfigMappingType,
+            },
+        }
+
         let mut map = Mappings::with_capacity(access.size_hint().unwrap_or(0));
 
         while let Some((key, value)) = access.next_entry()? {
-            map.insert(key, value);
+            match value {
+                Mapping::Column(col) => map.insert(key, col, ConfigMappingType::Text),
+                Mapping::Typed { column, value_type } => map.insert(key, column, value_type),
+            }
         }
 
         Ok(map)
@@ -0,0 +1,357 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! LDAP message codec with support for controls ldap3_proto does not know.
+//!
+//! ldap3_proto refuses messages with controls it can not decode, so these are taken out
+//! of the message before it is decoded and returned separately.
+
+use std::io;
+
+use lber::common::TagClass;
+use lber::structure::{StructureTag, PL};
+use lber::structures::{ASNTag, Enumerated, Integer, OctetString, Sequence, Tag};
+use lber::universal::Types;
+use lber::Parser;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::error::LdapProtoError;
+use ldap3_proto::proto::{LdapMsg, LdapResultCode};
+use tokio_util::bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+pub const SORT_OID: &str = "1.2.840.113556.1.4.473";
+const SORT_RESULT_OID: &str = "1.2.840.113556.1.4.474";
+pub const VLV_OID: &str = "2.16.840.1.113730.3.4.9";
+const VLV_RESULT_OID: &str = "2.16.840.1.113730.3.4.10";
+
+/// Result codes of the virtual list view response that ldap3_proto does not know.
+pub const SORT_CONTROL_MISSING: i64 = 60;
+pub const OFFSET_RANGE_ERROR: i64 = 61;
+
+/// Same limit as the ldap3_proto codec.
+const MAX_BER_SIZE: usize = ldap3_proto::DEFAULT_MAX_BER_SIZE;
+
+/// An LDAP message together with the controls ldap3_proto does not know.
+pub struct Message {
+    pub msg: LdapMsg,
+    pub controls: Vec<Control>,
+}
+
+impl From<LdapMsg> for Message {
+    fn from(msg: LdapMsg) -> Self {
+        Self {
+            msg,
+            controls: Vec::new(),
+        }
+    }
+}
+
+pub enum Control {
+    /// Server side sorting request (RFC 2891)
+    Sort { critical: bool, keys: Vec<SortKey> },
+    /// Server side sorting response, with the attribute that could not be sorted by
+    SortResult {
+        code: LdapResultCode,
+        attribute: Option<String>,
+    },
+    /// Virtual list view request (draft-ietf-ldapext-ldapv3-vlv), the entries before and
+    /// after the target to return
+    Vlv {
+        before: u32,
+        after: u32,
+        target: VlvTarget,
+    },
+    /// Virtual list view response with the 1-based position of the target,
+    /// the number of entries and a result code
+    VlvResult { target: u32, count: u32, code: i64 },
+    /// A request control that is not supported at all
+    Unknown { oid: String, critical: bool },
+}
+
+pub struct SortKey {
+    pub attribute: String,
+    pub ordering_rule: Option<String>,
+    pub reverse: bool,
+}
+
+pub enum VlvTarget {
+    /// 1-based position in the list, relative to the number of entries the client knows of
+    /// unless that is 0
+    Offset { offset: u32, count: u32 },
+    /// The first entry whose first sort key is greater than or equal to the value
+    GreaterOrEqual(String),
+}
+
+pub struct LdapCodec;
+
+impl Decoder for LdapCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let (rem, tag) = match Parser::new().parse(buf) {
+            Ok(r) => r,
+            // Need more data
+            Err(lber::Err::Incomplete(_)) => return Ok(None),
+            Err(_) => return Err(io::Error::other("lber parser")),
+        };
+        let size = buf.len() - rem.len();
+        if size > MAX_BER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "lber request too large",
+            ));
+        }
+        buf.advance(size);
+
+        let (tag, controls) = take_controls(tag);
+        let msg = LdapMsg::try_from(tag).map_err(io::Error::other)?;
+        Ok(Some(Message { msg, controls }))
+    }
+}
+
+impl Encoder<Message> for LdapCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, buf: &mut BytesMut) -> io::Result<()> {
+        let mut tag: StructureTag = message.msg.into();
+        if !message.controls.is_empty() {
+            if let PL::C(seq) = &mut tag.payload {
+                // The controls of the message are the optional third element
+                if seq.len() < 3 {
+                    seq.push(StructureTag {
+                        class: TagClass::Context,
+                        id: 0,
+                        payload: PL::C(Vec::new()),
+                    });
+                }
+                if let Some(PL::C(controls)) = seq.last_mut().map(|t| &mut t.payload) {
+                    controls.extend(message.controls.into_iter().filter_map(encode_control));
+                }
+            }
+        }
+        lber::write::encode_into(buf, tag)
+    }
+}
+
+/// Removes the controls ldap3_proto can not decode from the message.
+///
+/// Malformed controls it knows are left in place, so decoding the message fails like before.
+fn take_controls(mut tag: StructureTag) -> (StructureTag, Vec<Control>) {
+    let mut taken = Vec::new();
+    if let PL::C(seq) = &mut tag.payload {
+        if let Some(StructureTag {
+            class: TagClass::Context,
+            id: 0,
+            payload: PL::C(controls),
+        }) = seq.get_mut(2)
+        {
+            controls.retain(|ctrl| match decode_control(ctrl) {
+                Some(control) => {
+                    taken.push(control);
+                    false
+                }
+                None => true,
+            });
+        }
+    }
+    (tag, taken)
+}
+
+/// Decodes a control unless ldap3_proto knows it or it is malformed.
+fn decode_control(tag: &StructureTag) -> Option<Control> {
+    let mut parts = tag.clone().expect_constructed()?.into_iter();
+    let oid = parts
+        .next()
+        .and_then(|t| t.match_id(Types::OctetString as u64))
+        .and_then(|t| t.expect_primitive())
+        .and_then(|v| String::from_utf8(v).ok())?;
+    let mut critical = false;
+    let mut value = None;
+    for part in parts {
+        match part.id {
+            id if id == Types::Boolean as u64 => critical = ber_bool(part.expect_primitive()?)?,
+            id if id == Types::OctetString as u64 => value = Some(part.expect_primitive()?),
+            _ => return None,
+        }
+    }
+
+    match oid.as_str() {
+        SORT_OID => {
+            let value = Parser::new().parse(&value?).ok()?.1;
+            let keys = value
+                .expect_constructed()?
+                .into_iter()
+                .map(decode_sort_key)
+                .collect::<Option<Vec<_>>>()?;
+            Some(Control::Sort { critical, keys })
+        }
+        VLV_OID => {
+            let value = Parser::new().parse(&value?).ok()?.1;
+            decode_vlv(value)
+        }
+        _ => match LdapControl::try_from(tag.clone()) {
+            Err(LdapProtoError::ControlUnknown) => Some(Control::Unknown { oid, critical }),
+            _ => None,
+        },
+    }
+}
+
+/// `SEQUENCE { attributeType, orderingRule [0] OPTIONAL, reverseOrder [1] BOOLEAN DEFAULT FALSE }`
+fn decode_sort_key(tag: StructureTag) -> Option<SortKey> {
+    let mut parts = tag.expect_constructed()?.into_iter();
+    let attribute = parts
+        .next()
+        .and_then(|t| t.match_class(TagClass::Universal))
+        .and_then(|t| t.expect_primitive())
+        .and_then(|v| String::from_utf8(v).ok())?;
+    let mut key = SortKey {
+        attribute,
+        ordering_rule: None,
+        reverse: false,
+    };
+    for part in parts {
+        match (part.class, part.id) {
+            (TagClass::Context, 0) => {
+                key.ordering_rule = Some(String::from_utf8(part.expect_primitive()?).ok()?)
+            }
+            (TagClass::Context, 1) => key.reverse = ber_bool(part.expect_primitive()?)?,
+            _ => return None,
+        }
+    }
+    Some(key)
+}
+
+/// `SEQUENCE { beforeCount, afterCount, target CHOICE { byOffset [0] SEQUENCE { offset,
+/// contentCount }, greaterThanOrEqual [1] AssertionValue }, contextID OPTIONAL }`
+fn decode_vlv(tag: StructureTag) -> Option<Control> {
+    let mut parts = tag.expect_constructed()?.into_iter();
+    let mut count = || {
+        parts
+            .next()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::Integer as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(ber_u32)
+    };
+    let before = count()?;
+    let after = count()?;
+    // The context ID is not needed, every request is answered from the database
+    let target = parts.next()?;
+    let target = match (target.class, target.id) {
+        (TagClass::Context, 0) => {
+            let mut offset = target.expect_constructed()?.into_iter().map(|t| {
+                t.match_id(Types::Integer as u64)
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_u32)
+            });
+            VlvTarget::Offset {
+                offset: offset.next()??,
+                count: offset.next()??,
+            }
+        }
+        (TagClass::Context, 1) => {
+            VlvTarget::GreaterOrEqual(String::from_utf8(target.expect_primitive()?).ok()?)
+        }
+        _ => return None,
+    };
+    Some(Control::Vlv {
+        before,
+        after,
+        target,
+    })
+}
+
+fn ber_bool(value: Vec<u8>) -> Option<bool> {
+    match value[..] {
+        [b] => Some(b != 0),
+        _ => None,
+    }
+}
+
+/// Non-negative integer, in two's complement big-endian.
+fn ber_u32(value: Vec<u8>) -> Option<u32> {
+    if value.is_empty() || value.len() > 5 || value[0] & 0x80 != 0 {
+        return None;
+    }
+    let value = value.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+    value.try_into().ok()
+}
+
+/// Only response controls are encoded.
+fn encode_control(control: Control) -> Option<StructureTag> {
+    let (oid, value) = match control {
+        Control::SortResult { code, attribute } => {
+            let mut inner = vec![Tag::Enumerated(Enumerated {
+                inner: code as i64,
+                ..Default::default()
+            })];
+            if let Some(attribute) = attribute {
+                inner.push(Tag::OctetString(OctetString {
+                    class: TagClass::Context,
+                    id: 0,
+                    inner: attribute.into_bytes(),
+                }));
+            }
+            (SORT_RESULT_OID, inner)
+        }
+        Control::VlvResult {
+            target,
+            count,
+            code,
+        } => {
+            let inner = vec![
+                Tag::Integer(Integer {
+                    inner: target as i64,
+                    ..Default::default()
+                }),
+                Tag::Integer(Integer {
+                    inner: count as i64,
+                    ..Default::default()
+                }),
+                Tag::Enumerated(Enumerated {
+                    inner: code,
+                    ..Default::default()
+                }),
+            ];
+            (VLV_RESULT_OID, inner)
+        }
+        Control::Sort { .. } | Control::Vlv { .. } | Control::Unknown { .. } => return None,
+    };
+
+    let mut bytes = BytesMut::new();
+    lber::write::encode_into(
+        &mut bytes,
+        Tag::Sequence(Sequence {
+            inner: value,
+            ..Default::default()
+        })
+        .into_structure(),
+    )
+    .expect("Encoding to memory does not fail");
+    let tag = Tag::Sequence(Sequence {
+        inner: vec![
+            Tag::OctetString(OctetString {
+                inner: oid.as_bytes().to_vec(),
+                ..Default::default()
+            }),
+            Tag::OctetString(OctetString {
+                inner: bytes.to_vec(),
+                ..Default::default()
+            }),
+        ],
+        ..Default::default()
+    });
+    Some(tag.into_structure())
+}
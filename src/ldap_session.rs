@@ -12,44 +12,359 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use futures::TryStreamExt;
+use ldap3_proto::control::LdapControl;
 use ldap3_proto::proto::{
-    LdapFilter, LdapMsg, LdapPartialAttribute, LdapResultCode, LdapSearchResultEntry,
+    LdapExtendedResponse, LdapMsg, LdapOp, LdapPartialAttribute, LdapResult, LdapResultCode,
+    LdapSearchResultEntry,
 };
 use ldap3_proto::simple::{CompareRequest, SearchRequest, SimpleBindRequest, WhoamiRequest};
 use ldap3_proto::LdapSearchScope;
-use sqlx::Row;
 
+use crate::acl::{Access, Identity};
+use crate::backend::*;
 use crate::config::*;
+use crate::ldap_codec::{
+    Control, Message, VlvTarget, OFFSET_RANGE_ERROR, SORT_CONTROL_MISSING, SORT_OID, VLV_OID,
+};
+use crate::password;
+
+pub const STARTTLS_OID: &str = "1.3.6.1.4.1.1466.20037";
+pub const WHOAMI_OID: &str = "1.3.6.1.4.1.4203.1.11.3";
+const PAGED_RESULTS_OID: &str = "1.2.840.113556.1.4.319";
 
 pub struct LdapSession {
     conf: Arc<Config>,
-    db_pool: Arc<sqlx::postgres::PgPool>,
+    backend: Arc<dyn Backend>,
     dn: String,
+    /// The connection is encrypted
+    tls: bool,
+    /// Rights of the bound identity
+    access: Access,
+    /// Bound with a password
+    authenticated: bool,
+    /// Anonymous access is enabled for the address of the client
+    anonymous_allowed: bool,
 }
 
 impl LdapSession {
-    pub fn new(conf: Arc<Config>, db_pool: Arc<sqlx::postgres::PgPool>) -> Self {
+    pub fn new(conf: Arc<Config>, backend: Arc<dyn Backend>, tls: bool, paddr: IpAddr) -> Self {
+        // IPv4 clients of a dual stack listener have mapped addresses
+        let paddr = paddr.to_canonical();
+        let anonymous_allowed = conf.ldap.anonymous
+            && (conf.ldap.anonymous_networks.is_empty()
+                || conf
+                    .ldap
+                    .anonymous_networks
+                    .iter()
+                    .any(|net| net.contains(&paddr)));
         Self {
+            access: Access::new(&conf, Identity::Anonymous),
             conf,
-            db_pool,
+            backend,
             dn: String::default(),
+            tls,
+            authenticated: false,
+            anonymous_allowed,
+        }
+    }
+
+    /// The configuration demands TLS, but the connection is not encrypted yet.
+    fn tls_required(&self) -> bool {
+        !self.tls && self.conf.server.tls.as_ref().is_some_and(|tls| tls.require)
+    }
+
+    /// The error for operations the configuration does not allow without a bind.
+    fn anonymous_denied(&self) -> Option<(LdapResultCode, &'static str)> {
+        if self.authenticated {
+            None
+        } else if self.dn.is_empty() && self.conf.ldap.require_bind {
+            Some((LdapResultCode::OperationsError, "A bind is required"))
+        } else if !self.anonymous_allowed {
+            Some((
+                LdapResultCode::InsufficentAccessRights,
+                "Anonymous access is not allowed",
+            ))
+        } else {
+            None
         }
     }
 
     pub async fn do_bind(&mut self, sbr: &SimpleBindRequest) -> LdapMsg {
-        if sbr.dn == "" && sbr.pw == "" {
+        if self.tls_required() {
+            return sbr.gen_error(
+                LdapResultCode::ConfidentialityRequired,
+                "TLS is required, use StartTLS".to_owned(),
+            );
+        }
+
+        // A failed bind leaves the connection unauthenticated
+        self.dn = String::new();
+        self.access = Access::new(&self.conf, Identity::Anonymous);
+        self.authenticated = false;
+        if sbr.dn.is_empty() && sbr.pw.is_empty() {
+            if !self.anonymous_allowed {
+                return sbr.gen_error(
+                    LdapResultCode::InappropriateAuthentication,
+                    "Anonymous binds are not allowed".to_owned(),
+                );
+            }
             self.dn = "Anonymous".to_owned();
 
             sbr.gen_success()
+        } else if sbr.pw.is_empty() {
+            // Unauthenticated binds (RFC 4513 5.1.2) would look like successful ones to careless clients
+            sbr.gen_error(
+                LdapResultCode::UnwillingToPerform,
+                "Unauthenticated binds are not allowed".to_owned(),
+            )
         } else {
-            sbr.gen_invalid_cred()
+            match self.authenticate(&sbr.dn, &sbr.pw).await {
+                Ok(Some((dn, access))) => {
+                    self.dn = dn;
+                    self.access = access;
+                    self.authenticated = true;
+                    sbr.gen_success()
+                }
+                Ok(None) => sbr.gen_invalid_cred(),
+                Err(err) => {
+                    let (code, message) = err.ldap_result();
+                    sbr.gen_error(code, message.to_owned())
+                }
+            }
+        }
+    }
+
+    /// Checks the password of a service account or an entry and returns its DN and rights.
+    async fn authenticate(
+        &self,
+        dn: &str,
+        pw: &str,
+    ) -> Result<Option<(String, Access)>, BackendError> {
+        // Service accounts take precedence over rows with the same DN
+        if let Some(account) = self
+            .conf
+            .ldap
+            .accounts
+            .iter()
+            .find(|account| account.dn.eq_ignore_ascii_case(dn))
+        {
+            if !self
+                .check_password(&account.dn, pw, &account.password)
+                .await
+            {
+                return Ok(None);
+            }
+            let access = Access::new(&self.conf, Identity::Account(&account.dn));
+            return Ok(Some((account.dn.to_owned(), access)));
+        }
+
+        let cn = match self.cn_of_dn(dn) {
+            Some(cn) => cn,
+            None => return Ok(None),
+        };
+        let credentials = match self.backend.credentials(cn).await? {
+            Some(credentials) => credentials,
+            None => return Ok(None),
+        };
+        let dn = self.entry_dn(&credentials.cn);
+        if !self.check_password(&dn, pw, &credentials.password).await {
+            return Ok(None);
         }
+        let access = Access::new(&self.conf, Identity::User(&dn));
+        Ok(Some((dn, access)))
     }
 
-    pub async fn do_search(&mut self, lsr: &SearchRequest, size_limit: i32) -> Vec<LdapMsg> {
+    /// Hashes in unsupported formats are logged and never match.
+    ///
+    /// The hash functions are slow on purpose, so they run on the blocking thread pool
+    /// instead of stalling the other sessions of the worker thread. The seccomp filter
+    /// does not allow starting threads, so they are verified in place with it.
+    async fn check_password(&self, dn: &str, pw: &str, hash: &str) -> bool {
+        let verified = if self.conf.server.seccomp {
+            password::verify(pw, hash)
+        } else {
+            let (pw, hash) = (pw.to_owned(), hash.to_owned());
+            match tokio::task::spawn_blocking(move || password::verify(&pw, &hash)).await {
+                Ok(verified) => verified,
+                Err(err) => {
+                    log::error!("Checking the password of {} failed: {}", dn, err);
+                    return false;
+                }
+            }
+        };
+        verified.unwrap_or_else(|err| {
+            log::warn!("Can not check the password of {}: {}", dn, err);
+            false
+        })
+    }
+
+    /// The value of `cn=<value>,<suffix>`.
+    fn cn_of_dn<'a>(&self, dn: &'a str) -> Option<&'a str> {
+        let suffix = &self.conf.ldap.suffix;
+        let rdn = if suffix.is_empty() {
+            dn
+        } else {
+            let split = dn.len().checked_sub(suffix.len() + 1)?;
+            let (rdn, dn_suffix) = (dn.get(..split)?, dn.get(split..)?);
+            if !dn_suffix.starts_with(',') || !dn_suffix[1..].eq_ignore_ascii_case(suffix) {
+                return None;
+            }
+            rdn
+        };
+        let (attr, value) = rdn.split_once('=')?;
+        (attr.eq_ignore_ascii_case("cn") && !value.is_empty() && !value.contains([',', '=']))
+            .then_some(value)
+    }
+
+    fn entry_dn(&self, cn: &str) -> String {
+        let mut dn = "cn=".to_owned() + cn;
+        if !self.conf.ldap.suffix.is_empty() {
+            dn.push(',');
+            dn.push_str(&self.conf.ldap.suffix);
+        }
+        dn
+    }
+
+    /// Answers a search, `other_controls` are the controls ldap3_proto does not know.
+    pub async fn do_search(
+        &mut self,
+        lsr: &SearchRequest,
+        size_limit: i32,
+        controls: &[LdapControl],
+        other_controls: &[Control],
+    ) -> Vec<Message> {
+        let mut sort = Vec::new();
+        let mut sort_result = None;
+        let mut vlv = None;
+        for control in other_controls {
+            match control {
+                // Server side sorting (RFC 2891)
+                Control::Sort { critical, keys } => {
+                    for key in keys {
+                        let code = match self.conf.mappings.get(&key.attribute) {
+                            // Sorting reveals values like filters do
+                            Some((attr_lower, _, _)) if self.access.can_search(attr_lower) => {
+                                if key
+                                    .ordering_rule
+                                    .as_deref()
+                                    .is_none_or(is_case_ignore_ordering)
+                                {
+                                    sort.push(Sort {
+                                        attribute: attr_lower,
+                                        reverse: key.reverse,
+                                    });
+                                    continue;
+                                }
+                                LdapResultCode::InappropriateMatching
+                            }
+                            _ => LdapResultCode::NoSuchAttribute,
+                        };
+                        if *critical {
+                            return vec![lsr
+                                .gen_error(
+                                    LdapResultCode::UnavailableCriticalExtension,
+                                    format!("Can not sort by {}", key.attribute),
+                                )
+                                .into()];
+                        }
+                        // Without criticality the entries are returned unsorted
+                        sort.clear();
+                        sort_result = Some((code, Some(key.attribute.to_owned())));
+                        break;
+                    }
+                    sort_result.get_or_insert((LdapResultCode::Success, None));
+                }
+                Control::Vlv {
+                    before,
+                    after,
+                    target,
+                } => vlv = Some((*before, *after, target)),
+                Control::Unknown {
+                    oid,
+                    critical: true,
+                } => {
+                    return vec![lsr
+                        .gen_error(
+                            LdapResultCode::UnavailableCriticalExtension,
+                            format!("The control {} is not supported", oid),
+                        )
+                        .into()];
+                }
+                _ => {}
+            }
+        }
+
+        let mut response_controls = Vec::new();
+        if vlv.is_some() {
+            if controls
+                .iter()
+                .any(|ctrl| matches!(ctrl, LdapControl::SimplePagedResults { .. }))
+            {
+                return vec![lsr
+                    .gen_error(
+                        LdapResultCode::UnwillingToPerform,
+                        "Paged results and the virtual list view can not be combined".to_owned(),
+                    )
+                    .into()];
+            }
+            if sort.is_empty() {
+                return vec![Message {
+                    msg: lsr.gen_error(
+                        LdapResultCode::UnwillingToPerform,
+                        "The virtual list view requires the sort control".to_owned(),
+                    ),
+                    controls: vec![Control::VlvResult {
+                        target: 0,
+                        count: 0,
+                        code: SORT_CONTROL_MISSING,
+                    }],
+                }];
+            }
+        }
+
+        let mut results: Vec<Message> = self
+            .search(lsr, size_limit, controls, sort, vlv, &mut response_controls)
+            .await
+            .into_iter()
+            .map(Message::from)
+            .collect();
+        if let Some(done) = results.last_mut() {
+            if let Some((code, attribute)) = sort_result {
+                done.controls.push(Control::SortResult { code, attribute });
+            }
+            done.controls.append(&mut response_controls);
+        }
+        results
+    }
+
+    /// `vlv` are the entries before and after the target of a virtual list view,
+    /// its response control is added to `response_controls`.
+    async fn search(
+        &self,
+        lsr: &SearchRequest,
+        size_limit: i32,
+        controls: &[LdapControl],
+        sort: Vec<Sort<'_>>,
+        vlv: Option<(u32, u32, &VlvTarget)>,
+        response_controls: &mut Vec<Control>,
+    ) -> Vec<LdapMsg> {
+        // The root DSE stays readable for discovering StartTLS
+        if !(lsr.scope == LdapSearchScope::Base && lsr.base.is_empty()) {
+            if self.tls_required() {
+                return vec![lsr.gen_error(
+                    LdapResultCode::ConfidentialityRequired,
+                    "TLS is required, use StartTLS".to_owned(),
+                )];
+            }
+            if let Some((code, message)) = self.anonymous_denied() {
+                return vec![lsr.gen_error(code, message.to_owned())];
+            }
+        }
+
         let base_lower = lsr.base.to_ascii_lowercase();
         let suffix_lower = self.conf.ldap.suffix.to_lowercase();
         let mut cn_base_search: Option<String> = None;
@@ -69,6 +384,22 @@ impl LdapSession {
                                 atype: "namingContexts".to_owned(),
                                 vals: vec![self.conf.ldap.suffix.as_bytes().to_vec()],
                             },
+                            LdapPartialAttribute {
+                                atype: "supportedExtension".to_owned(),
+                                vals: if self.conf.server.tls.is_some() {
+                                    vec![WHOAMI_OID.into(), STARTTLS_OID.into()]
+                                } else {
+                                    vec![WHOAMI_OID.into()]
+                                },
+                            },
+                            LdapPartialAttribute {
+                                atype: "supportedControl".to_owned(),
+                                vals: vec![
+                                    PAGED_RESULTS_OID.into(),
+                                    SORT_OID.into(),
+                                    VLV_OID.into(),
+                                ],
+                            },
                         ],
                     }),
                     lsr.gen_success(),
@@ -76,9 +407,9 @@ impl LdapSession {
             } else if suffix_lower.ends_with(&format!(",{}", base_lower)) {
                 return vec![lsr.gen_success()];
             } else if base_lower == suffix_lower {
-                let leaf_short = suffix_lower[0..suffix_lower.find("=").unwrap()].to_owned();
+                let leaf_short = suffix_lower[0..suffix_lower.find('=').unwrap()].to_owned();
                 let leaf_name = suffix_lower
-                    [(suffix_lower.find("=").unwrap() + 1)..suffix_lower.find(",").unwrap()]
+                    [(suffix_lower.find('=').unwrap() + 1)..suffix_lower.find(',').unwrap()]
                     .to_owned();
                 let object_class = match &leaf_short as &str {
                     "dc" => "dcObject".to_owned(),
@@ -115,13 +446,11 @@ impl LdapSession {
                     lsr.gen_success(),
                 ];
             } else if base_lower.ends_with(&format!(",{}", &suffix_lower)) {
-                let ident = &base_lower[0..base_lower.len() - suffix_lower.len() - 1];
-                // TODO this can be improved
-                let ident_split: Vec<&str> = ident.split("=").take(3).collect();
-                if ident.contains(",") || ident_split.len() != 2 || ident_split[0] != "cn" {
-                    return vec![lsr.gen_error(LdapResultCode::NoSuchObject, "".to_owned())];
+                // The value keeps its case, entries are looked up case insensitively
+                match self.cn_of_dn(&lsr.base) {
+                    Some(cn) => cn_base_search = Some(cn.to_owned()),
+                    None => return vec![lsr.gen_error(LdapResultCode::NoSuchObject, String::new())],
                 }
-                cn_base_search = Some(ident_split[1].to_owned());
             } else {
                 return vec![lsr.gen_error(LdapResultCode::NoSuchObject, String::new())];
             }
@@ -130,111 +459,268 @@ impl LdapSession {
             return vec![lsr.gen_success()];
         }
 
+        // Simple paged results (RFC 2696), the cookie is the offset of the next page
+        let page = match controls.iter().find_map(|ctrl| match ctrl {
+            LdapControl::SimplePagedResults { size, cookie } => Some((*size, cookie)),
+            _ => None,
+        }) {
+            Some((size, cookie)) => {
+                let start = if cookie.is_empty() {
+                    0
+                } else {
+                    match <[u8; 4]>::try_from(cookie.as_slice()) {
+                        Ok(bytes) => u32::from_be_bytes(bytes),
+                        Err(_) => {
+                            return vec![lsr.gen_error(
+                                LdapResultCode::UnwillingToPerform,
+                                "Invalid paged results cookie".to_owned(),
+                            )]
+                        }
+                    }
+                };
+                // The size limit applies to all pages together
+                let remaining = match size_limit {
+                    0 => i64::MAX,
+                    limit => limit as i64 - start as i64,
+                };
+                // A size of 0 abandons the paged search
+                if size <= 0 || remaining <= 0 {
+                    return vec![paged_done(lsr.gen_success(), Vec::new())];
+                }
+                Some((
+                    start,
+                    i32::try_from(size.min(remaining)).unwrap_or(i32::MAX),
+                    remaining > size,
+                ))
+            }
+            None => None,
+        };
+
         //
-        // Build SQL query:
+        // Query the backend:
         //
 
-        let mut query = match build_select(&self.conf.mappings, &lsr) {
-            Ok(q) => q,
-            Err(e) => {
-                return e;
-            }
+        let all = lsr.attrs.is_empty() || lsr.attrs.contains(&"*".to_owned());
+        let attributes: Vec<(&str, &str)> = if all {
+            // Return all attributes
+            self.conf
+                .mappings
+                .iter()
+                .map(|(attr_lower, attr, _)| (attr_lower, attr))
+                .collect()
+        } else {
+            // Only requested attributes
+            lsr.attrs
+                .iter()
+                .filter_map(|attr_search| self.conf.mappings.get(attr_search))
+                .map(|(attr_lower, attr, _)| (attr_lower, attr))
+                .collect()
         };
 
-        query.push_str("FROM ");
-        query.push_str(&self.conf.sql.table);
-        query.push_str(" ");
-
-        let (q_filter, bindings) = match cn_base_search {
-            Some(cn) => {
+        let mut search = Search {
+            attributes: attributes
+                .iter()
+                .map(|(attr_lower, _)| *attr_lower)
+                .collect(),
+            target: match &cn_base_search {
                 // Base scope, return just one object
-                let mut q = "WHERE ".to_owned();
-                let (_, _, col) = self.conf.mappings.get("cn").unwrap();
-                q.push_str(col);
-                q.push_str(" = $1 ");
-                (q, vec![cn])
-            }
-            None => {
+                Some(cn) => SearchTarget::Cn(cn),
                 // Search the complete dn
-                match build_filter(&self.conf.mappings, &lsr) {
-                    Ok(x) => x,
-                    Err(e) => {
-                        return e;
-                    }
-                }
-            }
+                None => SearchTarget::Filter(&lsr.filter),
+            },
+            size_limit: match page {
+                // One more entry tells whether there is another page
+                Some((_, size, true)) => size.saturating_add(1),
+                Some((_, size, false)) => size,
+                None => size_limit,
+            },
+            sort,
+            offset: page.map(|(start, _, _)| start),
+            access: &self.access,
+        };
+        let failed = |err: BackendError| {
+            let (code, message) = err.ldap_result();
+            vec![lsr.gen_error(code, message.to_owned())]
         };
-        query.push_str(&q_filter);
 
-        if size_limit > 0 {
-            query.push_str(&format!("LIMIT {}", size_limit));
-        }
+        // Virtual list view, the window around the target entry
+        if let Some((before, after, target)) = vlv {
+            let count = match self.backend.count(&search, None).await {
+                Ok(count) => count,
+                Err(err) => return failed(err),
+            };
+            let position = match target {
+                VlvTarget::Offset { offset: 0, .. } => {
+                    response_controls.push(Control::VlvResult {
+                        target: 0,
+                        count,
+                        code: OFFSET_RANGE_ERROR,
+                    });
+                    return vec![lsr.gen_error(
+                        LdapResultCode::UnwillingToPerform,
+                        "The virtual list view offset starts at 1".to_owned(),
+                    )];
+                }
+                // Scaled to the actual number of entries if the client assumes another one
+                VlvTarget::Offset {
+                    offset,
+                    count: known,
+                } => if *known == 0 || *known == count {
+                    *offset
+                } else if offset >= known {
+                    count
+                } else {
+                    (*offset as u64 * count as u64 / *known as u64) as u32
+                }
+                .clamp(1, count.max(1)),
+                // The position after the last entry if all sort before the value
+                VlvTarget::GreaterOrEqual(value) => {
+                    match self.backend.count(&search, Some(value)).await {
+                        Ok(preceding) => preceding.saturating_add(1),
+                        Err(err) => return failed(err),
+                    }
+                }
+            };
+            response_controls.push(Control::VlvResult {
+                target: position,
+                count,
+                code: LdapResultCode::Success as i64,
+            });
 
-        if log::log_enabled!(log::Level::Debug) {
-            log::debug!("Query: {}", query);
-            if !bindings.is_empty() {
-                log::debug!("Params: \"{}\"", bindings.join("\", \""));
+            let first = position.saturating_sub(before).max(1);
+            let last = position.saturating_add(after).min(count);
+            let mut size = (last + 1).saturating_sub(first);
+            if size_limit > 0 {
+                size = size.min(size_limit as u32);
+            }
+            if size == 0 {
+                return vec![lsr.gen_success()];
             }
+            search.size_limit = size.try_into().unwrap_or(i32::MAX);
+            search.offset = Some(first - 1);
         }
 
-        let mut rows = {
-            let mut q = sqlx::query(&query);
-            for b in bindings {
-                q = q.bind(b);
-            }
-            q.fetch(self.db_pool.as_ref())
-        };
+        let mut entries = self.backend.search(&search);
         let mut results: Vec<LdapMsg> = Vec::new();
+        let mut more = false;
 
-        while let Some(row) = rows.try_next().await.unwrap() {
-            let all = lsr.attrs.len() == 0 || lsr.attrs.contains(&"*".to_owned());
-            let mut attributes = Vec::with_capacity(if all {
-                self.conf.mappings.len()
-            } else {
-                lsr.attrs.len()
-            });
-
-            let mut add_attribute = |attr: String, col: &str| {
-                let value: Option<String> = row.try_get(col).unwrap();
+        loop {
+            if let Some((_, size, _)) = page {
+                if results.len() == size as usize {
+                    more = matches!(entries.try_next().await, Ok(Some(_)));
+                    break;
+                }
+            }
+            let entry = match entries.try_next().await {
+                Ok(Some(entry)) => entry,
+                Ok(None) => break,
+                Err(err) => return failed(err),
+            };
 
-                if let Some(x) = value.filter(|s| s.len() > 0) {
-                    attributes.push(LdapPartialAttribute {
-                        atype: attr,
-                        vals: vec![x.as_bytes().to_vec()],
+            let mut attributes_ldap = Vec::with_capacity(attributes.len());
+            for ((_, attr), value) in attributes.iter().zip(entry.values) {
+                if let Some(x) = value.filter(|s| !s.is_empty()) {
+                    // Add with proper case
+                    attributes_ldap.push(LdapPartialAttribute {
+                        atype: attr.to_string(),
+                        vals: vec![x.into_bytes()],
                     })
                 };
-            };
-            if all {
-                // Return all attributes
-                for (attr_lowercase, attr, _) in &self.conf.mappings {
-                    add_attribute(attr.to_string(), &attr_lowercase);
-                }
-            } else {
-                // Only requested attributes
-                for attr_search in &lsr.attrs {
-                    // Add with proper case
-                    if let Some((attr_lower, attr, _)) = self.conf.mappings.get(&attr_search) {
-                        add_attribute(attr.to_string(), attr_lower);
-                    }
-                }
             }
 
-            let mut dn = "cn=".to_owned() + row.try_get::<&str, _>("cn").unwrap();
-            if self.conf.ldap.suffix.len() > 0 {
-                dn.push_str(",");
-                dn.push_str(&self.conf.ldap.suffix);
-            }
-            results.push(lsr.gen_result_entry(LdapSearchResultEntry { dn, attributes }));
+            results.push(lsr.gen_result_entry(LdapSearchResultEntry {
+                dn: self.entry_dn(&entry.cn),
+                attributes: attributes_ldap,
+            }));
         }
 
-        results.push(lsr.gen_success());
+        results.push(match page {
+            Some((start, size, _)) => {
+                let cookie = if more {
+                    start.saturating_add(size as u32).to_be_bytes().to_vec()
+                } else {
+                    Vec::new()
+                };
+                paged_done(lsr.gen_success(), cookie)
+            }
+            None => lsr.gen_success(),
+        });
         results
     }
 
-    pub fn do_compare(&mut self, cp: &CompareRequest) -> LdapMsg {
-        cp.gen_error(
-            LdapResultCode::Other,
-            "Compare not implemented".to_owned())
+    /// Compares a mapped attribute of an entry with the same matching as equality filters.
+    pub async fn do_compare(&mut self, cp: &CompareRequest) -> LdapMsg {
+        if self.tls_required() {
+            return cp.gen_error(
+                LdapResultCode::ConfidentialityRequired,
+                "TLS is required, use StartTLS".to_owned(),
+            );
+        }
+        if let Some((code, message)) = self.anonymous_denied() {
+            return cp.gen_error(code, message.to_owned());
+        }
+
+        let cn = match self.cn_of_dn(&cp.entry) {
+            Some(cn) => cn,
+            None => return cp.gen_error(LdapResultCode::NoSuchObject, String::new()),
+        };
+        let attribute = match self.conf.mappings.get(&cp.atype) {
+            Some((attr_lower, _, _)) => attr_lower,
+            None => return cp.gen_error(LdapResultCode::UndefinedAttributeType, String::new()),
+        };
+        if !self.access.can_compare(attribute) {
+            return cp.gen_error(LdapResultCode::InsufficentAccessRights, String::new());
+        }
+
+        let compare = Compare {
+            cn,
+            attribute,
+            value: &cp.val,
+            access: &self.access,
+        };
+        match self.backend.compare(&compare).await {
+            Ok(Some(true)) => cp.gen_compare_true(),
+            Ok(Some(false)) => cp.gen_compare_false(),
+            Ok(None) => cp.gen_error(LdapResultCode::NoSuchObject, String::new()),
+            Err(err) => {
+                let (code, message) = err.ldap_result();
+                cp.gen_error(code, message.to_owned())
+            }
+        }
+    }
+
+    /// Answers a StartTLS request, the connection has to be upgraded when the result is a success.
+    pub fn do_starttls(&mut self, msgid: i32) -> LdapMsg {
+        let (code, message) = if self.tls {
+            (
+                LdapResultCode::OperationsError,
+                "TLS is already established",
+            )
+        } else if self.conf.server.tls.is_none() {
+            (LdapResultCode::Unavailable, "TLS is not configured")
+        } else if !self.dn.is_empty() {
+            (
+                LdapResultCode::OperationsError,
+                "StartTLS is not allowed after a bind",
+            )
+        } else {
+            (LdapResultCode::Success, "")
+        };
+        gen_extended_response(msgid, code, message, Some(STARTTLS_OID))
+    }
+
+    /// Marks the connection as encrypted after the StartTLS handshake.
+    pub fn set_tls(&mut self) {
+        self.tls = true;
+    }
+
+    pub fn do_unsupported_extended(&mut self, msgid: i32, name: &str) -> LdapMsg {
+        gen_extended_response(
+            msgid,
+            LdapResultCode::ProtocolError,
+            &format!("Unsupported extended operation {}", name),
+            None,
+        )
     }
 
     pub fn do_whoami(&mut self, wr: &WhoamiRequest) -> LdapMsg {
@@ -242,143 +728,688 @@ impl LdapSession {
     }
 }
 
-fn build_select(mappings: &Mappings, lsr: &SearchRequest) -> Result<String, Vec<LdapMsg>> {
-    let mut q = "SELECT ".to_owned();
-
-    let mut cols = Vec::new();
-    if lsr.attrs.len() > 0 && !lsr.attrs.contains(&"*".to_owned()) {
-        // Just hit the db with the requested attributes
-        let mut has_cn = false;
-        for attr_search in &lsr.attrs {
-            if let Some((attr_lower, _, col)) = mappings.get(&attr_search) {
-                if attr_lower == "cn" {
-                    has_cn = true;
-                }
-                cols.push(format!("{} AS {}", col, attr_lower));
-            }
+/// Entries are sorted case insensitively like the filters match.
+fn is_case_ignore_ordering(rule: &str) -> bool {
+    rule == "2.5.13.3" || rule.eq_ignore_ascii_case("caseIgnoreOrderingMatch")
+}
+
+/// Attaches the paged results control to the final message of a search.
+fn paged_done(mut done: LdapMsg, cookie: Vec<u8>) -> LdapMsg {
+    // The size is an estimate of the total number of entries, 0 if unknown
+    done.ctrl = vec![LdapControl::SimplePagedResults { size: 0, cookie }];
+    done
+}
+
+fn gen_extended_response(
+    msgid: i32,
+    code: LdapResultCode,
+    message: &str,
+    name: Option<&str>,
+) -> LdapMsg {
+    LdapMsg::new(
+        msgid,
+        LdapOp::ExtendedResponse(LdapExtendedResponse {
+            res: LdapResult {
+                code,
+                matcheddn: String::new(),
+                message: message.to_owned(),
+                referral: Vec::new(),
+            },
+            name: name.map(str::to_owned),
+            value: None,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use ldap3_proto::parse_ldap_filter_str;
+    use ldap3_proto::proto::LdapBindResponse;
+
+    use super::*;
+    use crate::ldap_codec::SortKey;
+    use crate::sql_backend::SqlBackend;
+    use crate::sql_pool::{self, SqlPool};
+
+    const SUFFIX: &str = "ou=People,dc=example,dc=com";
+
+    // A single connection that is never closed keeps the in-memory database,
+    // the [ldap] section is last so tests can add to it
+    const CONFIG: &str = r#"
+        [server]
+        [sql]
+        backend = "SQLite"
+        database = ":memory:"
+        read_only = false
+        table = "users"
+        password_column = "password"
+        [sql.pool]
+        max_connections = 1
+        min_connections = 1
+        idle_timeout = 0
+        max_lifetime = 0
+        [mappings]
+        cn = "uid"
+        sn = "surname"
+        mail = "email"
+        [ldap]
+        suffix = "ou=People,dc=example,dc=com"
+    "#;
+
+    /// `{SSHA}` hash of `secret`
+    const SECRET: &str = "{SSHA}QxWRhccNco81WkE5NvgyzxlVKSQBAgMEc2FsdA==";
+
+    fn config(ldap: &str) -> Arc<Config> {
+        Arc::new(toml::from_str(&format!("{}{}", CONFIG, ldap)).unwrap())
+    }
+
+    /// A backend on an in-memory database with a few entries.
+    async fn backend(conf: &Arc<Config>) -> Arc<dyn Backend> {
+        let pool = Arc::new(SqlPool::<sqlx::Sqlite>::default());
+        sql_pool::connect(conf.clone(), String::new(), pool.clone()).await;
+
+        let db = pool.get().unwrap();
+        sqlx::query("CREATE TABLE users (uid TEXT, surname TEXT, email TEXT, password TEXT)")
+            .execute(&db)
+            .await
+            .unwrap();
+        for (uid, surname, password) in [
+            ("alice", "Smith", Some(SECRET)),
+            ("bob", "jones", None),
+            ("carol", "Brown", None),
+            ("dave", "", None),
+            ("erin", "Adams", None),
+        ] {
+            sqlx::query("INSERT INTO users VALUES (?, ?, ?, ?)")
+                .bind(uid)
+                .bind(surname)
+                .bind(format!("{}@example.com", uid))
+                .bind(password)
+                .execute(&db)
+                .await
+                .unwrap();
         }
 
-        if !has_cn {
-            // cn is always required to build the dn
-            let (_, _, cn_col) = mappings.get("cn").unwrap();
-            cols.push(format!("{} AS cn", cn_col));
+        Arc::new(SqlBackend::new(conf.clone(), pool))
+    }
+
+    async fn session() -> LdapSession {
+        session_with("", IpAddr::from([127, 0, 0, 1])).await
+    }
+
+    /// A plain connection from the address, `ldap` is added to the [ldap] section.
+    async fn session_with(ldap: &str, paddr: IpAddr) -> LdapSession {
+        let conf = config(ldap);
+        let backend = backend(&conf).await;
+        LdapSession::new(conf, backend, false, paddr)
+    }
+
+    fn request(filter: &str, attrs: &[&str]) -> SearchRequest {
+        SearchRequest {
+            msgid: 1,
+            base: SUFFIX.to_owned(),
+            scope: LdapSearchScope::Subtree,
+            filter: parse_ldap_filter_str(filter).unwrap(),
+            attrs: attrs.iter().map(|attr| attr.to_string()).collect(),
         }
-    } else {
-        for (attr_lowercase, _, col) in mappings {
-            cols.push(format!("{} AS {}", col, attr_lowercase))
+    }
+
+    fn sort(attribute: &str, reverse: bool) -> Control {
+        Control::Sort {
+            critical: true,
+            keys: vec![SortKey {
+                attribute: attribute.to_owned(),
+                ordering_rule: None,
+                reverse,
+            }],
         }
     }
 
-    q.push_str(&cols.join(", "));
-    q.push_str(" ");
+    fn paged(size: i64, cookie: Vec<u8>) -> LdapControl {
+        LdapControl::SimplePagedResults { size, cookie }
+    }
 
-    Ok(q)
-}
+    /// The cn of the returned entries.
+    fn cns(results: &[Message]) -> Vec<String> {
+        results
+            .iter()
+            .filter_map(|result| match &result.msg.op {
+                LdapOp::SearchResultEntry(entry) => Some(
+                    entry
+                        .dn
+                        .strip_prefix("cn=")
+                        .and_then(|dn| dn.strip_suffix(&format!(",{}", SUFFIX)))
+                        .unwrap()
+                        .to_owned(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
 
-fn build_filter(
-    mappings: &Mappings,
-    lsr: &SearchRequest,
-) -> Result<(String, Vec<String>), Vec<LdapMsg>> {
-    let mut query = "WHERE ".to_owned();
-    let mut bindings = Vec::new();
-    // Translate filter recursively:
-    build_filter_inner(mappings, lsr, &lsr.filter, &mut query, &mut bindings)?;
-    Ok((query, bindings))
-}
+    fn code(msg: &LdapMsg) -> LdapResultCode {
+        match &msg.op {
+            LdapOp::SearchResultDone(res)
+            | LdapOp::CompareResult(res)
+            | LdapOp::BindResponse(LdapBindResponse { res, .. })
+            | LdapOp::ExtendedResponse(LdapExtendedResponse { res, .. }) => res.code.clone(),
+            _ => panic!("Not a result"),
+        }
+    }
 
-fn build_filter_inner(
-    mappings: &Mappings,
-    lsr: &SearchRequest,
-    ldap_filter: &LdapFilter,
-    query: &mut String,
-    bindings: &mut Vec<String>,
-) -> Result<(), Vec<LdapMsg>> {
-    let sanitize = |s: &str| {
-        // TODO proper escape
-        s.replace("%", "\\%").replace("_", "\\_")
-    };
-    let get_token = || format!("${}", bindings.len() + 1);
-    let get_mapping = |attr: &str| -> Result<&str, Vec<LdapMsg>> {
-        match mappings.get(attr) {
-            Some((_, _, col)) => Ok(col),
-            None => Ok("''"), //Err(vec![lsr.gen_operror(&format!("Unknown filter attribute: {}", attr))]),
+    /// The code of the final message and the cookie of its paged results control.
+    fn page_done(results: &[Message]) -> (LdapResultCode, Vec<u8>) {
+        let done = &results.last().unwrap().msg;
+        match &done.ctrl[..] {
+            [LdapControl::SimplePagedResults { cookie, .. }] => (code(done), cookie.clone()),
+            _ => panic!("No paged results control"),
         }
-    };
-    let mut join_filter_group = |filters: &Vec<LdapFilter>,
-                                 sep: &str,
-                                 bindings: &mut Vec<String>|
-     -> Result<(), Vec<LdapMsg>> {
-        if filters.len() > 0 {
-            query.push_str("(");
-            let mut i = filters.iter();
-            let mut f = i.next();
-            loop {
-                build_filter_inner(mappings, lsr, f.unwrap(), query, bindings)?;
-                f = i.next();
-                if f.is_none() {
-                    break;
-                }
-                query.push_str(sep);
-            }
-            query.push_str(") ");
+    }
+
+    /// The target position, the count and the result code of the virtual list view.
+    fn vlv_result(results: &[Message]) -> (u32, u32, i64) {
+        results
+            .last()
+            .unwrap()
+            .controls
+            .iter()
+            .find_map(|control| match control {
+                Control::VlvResult {
+                    target,
+                    count,
+                    code,
+                } => Some((*target, *count, *code)),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    fn anonymous_bind() -> SimpleBindRequest {
+        SimpleBindRequest {
+            msgid: 1,
+            dn: String::new(),
+            pw: String::new(),
         }
-        Ok(())
-    };
-
-    match ldap_filter {
-        LdapFilter::And(filters) => join_filter_group(filters, "AND ", bindings),
-        LdapFilter::Or(filters) => join_filter_group(filters, "OR ", bindings),
-        LdapFilter::Not(filter) => {
-            query.push_str("(NOT ");
-            build_filter_inner(mappings, lsr, filter, query, bindings)?;
-            query.push_str(") ");
-            Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_base() {
+        let mut session = session().await;
+        let base = |dn: &str| SearchRequest {
+            scope: LdapSearchScope::Base,
+            base: format!("{},{}", dn, SUFFIX.to_uppercase()),
+            ..request("(objectClass=*)", &["sn"])
+        };
+
+        // The DN as returned by the server
+        let results = session.do_search(&base("cn=Alice"), 0, &[], &[]).await;
+        assert_eq!(cns(&results), ["alice"]);
+        let results = session.do_search(&base("CN=ALICE"), 0, &[], &[]).await;
+        assert_eq!(cns(&results), ["alice"]);
+
+        let results = session.do_search(&base("cn=nobody"), 0, &[], &[]).await;
+        assert!(cns(&results).is_empty());
+        for dn in ["sn=Smith", "cn=alice,ou=x", "cn="] {
+            let results = session.do_search(&base(dn), 0, &[], &[]).await;
+            assert_eq!(
+                code(&results[0].msg),
+                LdapResultCode::NoSuchObject,
+                "{}",
+                dn
+            );
         }
-        LdapFilter::Equality(attr, value) => {
-            let col = get_mapping(attr)?;
-            query.push_str("LOWER(");
-            query.push_str(col);
-            query.push_str(") = LOWER(");
-            query.push_str(&get_token());
-            query.push_str(") ");
-            bindings.push(sanitize(value));
-            Ok(())
+    }
+
+    #[tokio::test]
+    async fn search() {
+        let mut session = session().await;
+
+        let results = session
+            .do_search(&request("(sn=j*)", &["sn", "mail"]), 0, &[], &[])
+            .await;
+        assert_eq!(cns(&results), ["bob"]);
+        let LdapOp::SearchResultEntry(entry) = &results[0].msg.op else {
+            panic!("Not an entry");
+        };
+        let attributes: Vec<(&str, &[u8])> = entry
+            .attributes
+            .iter()
+            .map(|attr| (attr.atype.as_str(), attr.vals[0].as_slice()))
+            .collect();
+        assert_eq!(
+            attributes,
+            [("sn", &b"jones"[..]), ("mail", &b"bob@example.com"[..])]
+        );
+        assert_eq!(code(&results[1].msg), LdapResultCode::Success);
+
+        // Attributes that are not mapped match nothing
+        let results = session
+            .do_search(&request("(!(foo=bar))", &[]), 0, &[], &[])
+            .await;
+        assert!(cns(&results).is_empty());
+
+        let results = session
+            .do_search(&request("(cn=*)", &["cn"]), 2, &[], &[])
+            .await;
+        assert_eq!(cns(&results).len(), 2);
+    }
+
+    const TLS: &str = r#"
+        [server.tls]
+        cert = "cert.pem"
+        key = "key.pem"
+    "#;
+
+    #[tokio::test]
+    async fn tls_required() {
+        let mut session = session_with(
+            &format!("{}require = true", TLS),
+            IpAddr::from([127, 0, 0, 1]),
+        )
+        .await;
+
+        // The root DSE announces StartTLS
+        let root_dse = SearchRequest {
+            scope: LdapSearchScope::Base,
+            base: String::new(),
+            ..request("(objectClass=*)", &[])
+        };
+        let results = session.do_search(&root_dse, 0, &[], &[]).await;
+        let LdapOp::SearchResultEntry(entry) = &results[0].msg.op else {
+            panic!("Not an entry");
+        };
+        let extensions = entry
+            .attributes
+            .iter()
+            .find(|attr| attr.atype == "supportedExtension")
+            .unwrap();
+        assert!(extensions.vals.contains(&STARTTLS_OID.into()));
+        assert_eq!(code(&results[1].msg), LdapResultCode::Success);
+
+        let results = session
+            .do_search(&request("(cn=*)", &[]), 0, &[], &[])
+            .await;
+        assert!(cns(&results).is_empty());
+        assert_eq!(
+            code(&results[0].msg),
+            LdapResultCode::ConfidentialityRequired
+        );
+        assert_eq!(
+            code(&session.do_bind(&anonymous_bind()).await),
+            LdapResultCode::ConfidentialityRequired
+        );
+
+        assert_eq!(code(&session.do_starttls(1)), LdapResultCode::Success);
+        session.set_tls();
+        let results = session
+            .do_search(&request("(cn=*)", &[]), 0, &[], &[])
+            .await;
+        assert_eq!(cns(&results).len(), 5);
+        assert_eq!(
+            code(&session.do_starttls(1)),
+            LdapResultCode::OperationsError
+        );
+    }
+
+    #[tokio::test]
+    async fn starttls_after_bind() {
+        // Not configured at all
+        let mut unconfigured = session().await;
+        assert_eq!(
+            code(&unconfigured.do_starttls(1)),
+            LdapResultCode::Unavailable
+        );
+
+        let mut session = session_with(TLS, IpAddr::from([127, 0, 0, 1])).await;
+        assert_eq!(
+            code(&session.do_bind(&anonymous_bind()).await),
+            LdapResultCode::Success
+        );
+        assert_eq!(
+            code(&session.do_starttls(1)),
+            LdapResultCode::OperationsError
+        );
+    }
+
+    #[tokio::test]
+    async fn bind() {
+        let mut session = session().await;
+        let bind = |dn: &str, pw: &str| SimpleBindRequest {
+            msgid: 1,
+            dn: format!("{},{}", dn, SUFFIX),
+            pw: pw.to_owned(),
+        };
+
+        // The DN of the entry is the stored one
+        let result = session.do_bind(&bind("CN=ALICE", "secret")).await;
+        assert_eq!(code(&result), LdapResultCode::Success);
+        assert_eq!(session.dn, format!("cn=alice,{}", SUFFIX));
+        assert!(session.authenticated);
+
+        let result = session.do_bind(&bind("cn=alice", "Secret")).await;
+        assert_eq!(code(&result), LdapResultCode::InvalidCredentials);
+        assert!(session.dn.is_empty());
+        assert!(!session.authenticated);
+
+        // Without a password hash or a row
+        for dn in ["cn=bob", "cn=nobody"] {
+            let result = session.do_bind(&bind(dn, "secret")).await;
+            assert_eq!(code(&result), LdapResultCode::InvalidCredentials);
         }
-        LdapFilter::Substring(attr, filter) => {
-            let col = get_mapping(attr)?;
-            let mut filter_str = filter
-                .initial
-                .as_ref()
-                .map_or_else(|| String::default(), |s| sanitize(s) + "%");
-            if filter_str.is_empty() && !filter.any.is_empty() {
-                filter_str += "%";
-            }
-            for s in &filter.any {
-                filter_str += &sanitize(s);
-                filter_str += "%";
+
+        let result = session.do_bind(&bind("cn=alice", "")).await;
+        assert_eq!(code(&result), LdapResultCode::UnwillingToPerform);
+    }
+
+    #[tokio::test]
+    async fn bind_with_seccomp() {
+        let conf: Arc<Config> = Arc::new(
+            toml::from_str(&CONFIG.replace("[server]", "[server]\nseccomp = true")).unwrap(),
+        );
+        let backend = backend(&conf).await;
+        let mut session = LdapSession::new(conf, backend, false, IpAddr::from([127, 0, 0, 1]));
+        let bind = |pw: &str| SimpleBindRequest {
+            msgid: 1,
+            dn: format!("cn=alice,{}", SUFFIX),
+            pw: pw.to_owned(),
+        };
+
+        // Verified without the blocking thread pool
+        let result = session.do_bind(&bind("secret")).await;
+        assert_eq!(code(&result), LdapResultCode::Success);
+        let result = session.do_bind(&bind("wrong")).await;
+        assert_eq!(code(&result), LdapResultCode::InvalidCredentials);
+    }
+
+    #[tokio::test]
+    async fn bind_account() {
+        let mut session = session_with(
+            &format!(
+                "[[ldap.accounts]]\ndn = \"cn=pbx,dc=example,dc=com\"\npassword = \"{}\"\n",
+                SECRET
+            ),
+            IpAddr::from([127, 0, 0, 1]),
+        )
+        .await;
+        let bind = |pw: &str| SimpleBindRequest {
+            msgid: 1,
+            dn: "CN=PBX,dc=example,dc=com".to_owned(),
+            pw: pw.to_owned(),
+        };
+
+        let result = session.do_bind(&bind("wrong")).await;
+        assert_eq!(code(&result), LdapResultCode::InvalidCredentials);
+        assert!(!session.authenticated);
+
+        // The DN of the account is the configured one
+        let result = session.do_bind(&bind("secret")).await;
+        assert_eq!(code(&result), LdapResultCode::Success);
+        assert_eq!(session.dn, "cn=pbx,dc=example,dc=com");
+        assert!(session.authenticated);
+    }
+
+    #[tokio::test]
+    async fn anonymous_networks() {
+        let networks = "anonymous_networks = [\"10.0.0.0/8\"]";
+        let search = request("(cn=alice)", &[]);
+
+        let mut session = session_with(networks, IpAddr::from([192, 168, 1, 1])).await;
+        let results = session.do_search(&search, 0, &[], &[]).await;
+        assert_eq!(
+            code(&results[0].msg),
+            LdapResultCode::InsufficentAccessRights
+        );
+        let result = session.do_bind(&anonymous_bind()).await;
+        assert_eq!(code(&result), LdapResultCode::InappropriateAuthentication);
+        // Binding as an entry still works
+        let bind = SimpleBindRequest {
+            msgid: 1,
+            dn: format!("cn=alice,{}", SUFFIX),
+            pw: "secret".to_owned(),
+        };
+        let result = session.do_bind(&bind).await;
+        assert_eq!(code(&result), LdapResultCode::Success);
+        let results = session.do_search(&search, 0, &[], &[]).await;
+        assert_eq!(cns(&results), ["alice"]);
+
+        // IPv4 clients of a dual stack listener have mapped addresses
+        let inside = "::ffff:10.1.2.3".parse().unwrap();
+        let mut session = session_with(networks, inside).await;
+        let results = session.do_search(&search, 0, &[], &[]).await;
+        assert_eq!(cns(&results), ["alice"]);
+        let result = session.do_bind(&anonymous_bind()).await;
+        assert_eq!(code(&result), LdapResultCode::Success);
+    }
+
+    #[tokio::test]
+    async fn require_bind() {
+        let mut session = session_with("require_bind = true", IpAddr::from([127, 0, 0, 1])).await;
+        let search = request("(cn=alice)", &[]);
+
+        let results = session.do_search(&search, 0, &[], &[]).await;
+        assert_eq!(code(&results[0].msg), LdapResultCode::OperationsError);
+        // An anonymous bind suffices
+        let result = session.do_bind(&anonymous_bind()).await;
+        assert_eq!(code(&result), LdapResultCode::Success);
+        let results = session.do_search(&search, 0, &[], &[]).await;
+        assert_eq!(cns(&results), ["alice"]);
+
+        let session = session_with(
+            "require_bind = true\nanonymous = false",
+            IpAddr::from([127, 0, 0, 1]),
+        )
+        .await;
+        assert_eq!(
+            session.anonymous_denied(),
+            Some((LdapResultCode::OperationsError, "A bind is required"))
+        );
+    }
+
+    async fn compare(
+        session: &mut LdapSession,
+        entry: &str,
+        atype: &str,
+        val: &str,
+    ) -> LdapResultCode {
+        let request = CompareRequest {
+            msgid: 1,
+            entry: format!("{},{}", entry, SUFFIX),
+            atype: atype.to_owned(),
+            val: val.to_owned(),
+        };
+        code(&session.do_compare(&request).await)
+    }
+
+    #[tokio::test]
+    async fn compare_values() {
+        let mut session = session().await;
+        let session = &mut session;
+
+        assert_eq!(
+            compare(session, "cn=alice", "sn", "SMITH").await,
+            LdapResultCode::CompareTrue
+        );
+        assert_eq!(
+            compare(session, "cn=Alice", "sn", "Jones").await,
+            LdapResultCode::CompareFalse
+        );
+        assert_eq!(
+            compare(session, "cn=nobody", "sn", "Smith").await,
+            LdapResultCode::NoSuchObject
+        );
+        assert_eq!(
+            compare(session, "cn=alice", "foo", "bar").await,
+            LdapResultCode::UndefinedAttributeType
+        );
+    }
+
+    #[tokio::test]
+    async fn search_paged() {
+        let mut session = session().await;
+        let request = request("(cn=*)", &["cn"]);
+
+        // Ordered by cn without a sort control
+        let mut pages = Vec::new();
+        let mut cookie = Vec::new();
+        loop {
+            let results = session
+                .do_search(&request, 0, &[paged(2, cookie)], &[])
+                .await;
+            let (code, next) = page_done(&results);
+            assert_eq!(code, LdapResultCode::Success);
+            pages.push(cns(&results));
+            if next.is_empty() {
+                break;
             }
-            filter_str += &filter
-                .final_
-                .as_ref()
-                .map_or_else(|| String::default(), |s| sanitize(s));
-
-            query.push_str("LOWER(");
-            query.push_str(col);
-            query.push_str(") LIKE LOWER(");
-            query.push_str(&get_token());
-            query.push_str(") ");
-            bindings.push(filter_str);
-            Ok(())
-        }
-        LdapFilter::Present(attr) => {
-            let col = get_mapping(attr)?;
-            query.push_str(col);
-            query.push_str(" <> '' ");
-            Ok(())
+            cookie = next;
         }
-        _ => Err(vec![lsr.gen_error(
-            LdapResultCode::Other,
-            "Filter not implemented".to_owned(),
-        )]),
+        assert_eq!(
+            pages,
+            [vec!["alice", "bob"], vec!["carol", "dave"], vec!["erin"]]
+        );
+
+        // Pages of the sorted entries
+        let results = session
+            .do_search(
+                &request,
+                0,
+                &[paged(2, 2u32.to_be_bytes().to_vec())],
+                &[sort("sn", false)],
+            )
+            .await;
+        assert_eq!(cns(&results), ["bob", "alice"]);
+        assert_eq!(page_done(&results).1, 4u32.to_be_bytes());
+
+        // The size limit applies to all pages together
+        let results = session
+            .do_search(&request, 3, &[paged(2, 2u32.to_be_bytes().to_vec())], &[])
+            .await;
+        assert_eq!(cns(&results), ["carol"]);
+        assert!(page_done(&results).1.is_empty());
+
+        // Sizes beyond the range of the query are not truncated
+        let results = session
+            .do_search(&request, 0, &[paged(1 << 32, Vec::new())], &[])
+            .await;
+        assert_eq!(cns(&results).len(), 5);
+        assert!(page_done(&results).1.is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_sorted() {
+        let mut session = session().await;
+
+        // Case insensitive with empty values last
+        let results = session
+            .do_search(&request("(cn=*)", &["sn"]), 0, &[], &[sort("sn", false)])
+            .await;
+        assert_eq!(cns(&results), ["erin", "carol", "bob", "alice", "dave"]);
+        assert!(matches!(
+            results.last().unwrap().controls[..],
+            [Control::SortResult {
+                code: LdapResultCode::Success,
+                attribute: None
+            }]
+        ));
+
+        let results = session
+            .do_search(&request("(cn=*)", &["sn"]), 0, &[], &[sort("SN", true)])
+            .await;
+        assert_eq!(cns(&results), ["dave", "alice", "bob", "carol", "erin"]);
+
+        let results = session
+            .do_search(&request("(cn=*)", &["sn"]), 0, &[], &[sort("foo", false)])
+            .await;
+        assert!(cns(&results).is_empty());
+        assert_eq!(
+            code(&results[0].msg),
+            LdapResultCode::UnavailableCriticalExtension
+        );
+    }
+
+    #[tokio::test]
+    async fn search_vlv() {
+        let mut session = session().await;
+        let request = request("(cn=*)", &["sn"]);
+        let vlv = |before, after, target| Control::Vlv {
+            before,
+            after,
+            target,
+        };
+
+        let results = session
+            .do_search(
+                &request,
+                0,
+                &[],
+                &[
+                    sort("sn", false),
+                    vlv(
+                        1,
+                        1,
+                        VlvTarget::Offset {
+                            offset: 3,
+                            count: 0,
+                        },
+                    ),
+                ],
+            )
+            .await;
+        assert_eq!(cns(&results), ["carol", "bob", "alice"]);
+        assert_eq!(vlv_result(&results), (3, 5, 0));
+
+        // Scaled to the actual number of entries
+        let results = session
+            .do_search(
+                &request,
+                0,
+                &[],
+                &[
+                    sort("sn", false),
+                    vlv(
+                        0,
+                        0,
+                        VlvTarget::Offset {
+                            offset: 8,
+                            count: 10,
+                        },
+                    ),
+                ],
+            )
+            .await;
+        assert_eq!(cns(&results), ["alice"]);
+        assert_eq!(vlv_result(&results), (4, 5, 0));
+
+        let results = session
+            .do_search(
+                &request,
+                0,
+                &[],
+                &[
+                    sort("sn", false),
+                    vlv(0, 1, VlvTarget::GreaterOrEqual("C".to_owned())),
+                ],
+            )
+            .await;
+        assert_eq!(cns(&results), ["bob", "alice"]);
+        assert_eq!(vlv_result(&results), (3, 5, 0));
+
+        let results = session
+            .do_search(
+                &request,
+                0,
+                &[],
+                &[vlv(
+                    0,
+                    1,
+                    VlvTarget::Offset {
+                        offset: 1,
+                        count: 0,
+                    },
+                )],
+            )
+            .await;
+        assert_eq!(code(&results[0].msg), LdapResultCode::UnwillingToPerform);
+        assert_eq!(vlv_result(&results).2, SORT_CONTROL_MISSING);
     }
 }
@@ -20,28 +20,45 @@ use std::net;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+mod acl;
+mod backend;
+mod cologne;
 mod config;
+mod ldap_codec;
 mod ldap_session;
-use self::config::Config;
-use self::ldap_session::LdapSession;
+mod password;
+mod sql_backend;
+mod sql_pool;
+mod sql_query;
+mod tls;
+use self::backend::Backend;
+use self::config::{Config, ConfigSqlBackend};
+use self::ldap_codec::{LdapCodec, Message};
+use self::ldap_session::{LdapSession, STARTTLS_OID, WHOAMI_OID};
+use self::sql_backend::SqlBackend;
+use self::sql_pool::SqlPool;
+use self::tls::Tls;
 
 use clap::{Arg, ArgAction, ArgMatches, Command};
+use futures::future::BoxFuture;
 use futures::{SinkExt, StreamExt};
+use ldap3_proto::proto::{LdapMsg, LdapOp};
 use ldap3_proto::simple::*;
-use ldap3_proto::LdapCodec;
 use seccompiler::{
     BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
     SeccompRule, TargetArch,
 };
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
 
 static DEFAULT_CONFIG_FILE: &str = "/etc/sql2ldap.toml";
 static DEFAULT_USER: &str = "nobody";
 static DEFAULT_GROUP: &str = "nogroup";
 
 static SECCOMP_ARMED: AtomicBool = AtomicBool::new(false);
-thread_local!(static SECCOMP_INSTALLED: RefCell<bool> = RefCell::new(false));
+thread_local!(static SECCOMP_INSTALLED: RefCell<bool> = const { RefCell::new(false) });
 
 fn main() -> Result<(), String> {
     let cmd = load_command_line();
@@ -82,9 +99,24 @@ fn main() -> Result<(), String> {
 
     // Bind before dropping privileges:
     let addr = net::SocketAddr::new(config.server.ip, config.server.port);
-    let listener = std::net::TcpListener::bind(&addr)
+    let listener = std::net::TcpListener::bind(addr)
         .map_err(|err| format!("Can not bind to {}: {}", addr, err))?;
     listener.set_nonblocking(true).unwrap();
+    // The key is usually only readable by root
+    let tls_listener = match &config.server.tls {
+        Some(conf_tls) => {
+            let tls = Arc::new(Tls::load(conf_tls)?);
+            let addr = net::SocketAddr::new(config.server.ip, conf_tls.port);
+            let listener = std::net::TcpListener::bind(addr)
+                .map_err(|err| format!("Can not bind to {}: {}", addr, err))?;
+            listener.set_nonblocking(true).unwrap();
+            Some((listener, tls, addr))
+        }
+        None => None,
+    };
+
+    // The secret may only be readable before dropping privileges
+    let db_pass = config.sql.password()?;
 
     drop_privileges()?;
 
@@ -124,32 +156,100 @@ fn main() -> Result<(), String> {
         .build()
         .unwrap()
         .block_on(async {
-            let (con_opts, pool_opts) = build_pg_connect_options(&config);
-            let db_pool = Arc::new(
-                pool_opts
-                    .connect_with(con_opts)
-                    .await
-                    .map_err(|err| format!("Could not connect to database: {}", err))?,
-            );
-
-            // Apply seccomp filters after db connections where opened
-            SECCOMP_ARMED.store(true, Ordering::Release);
+            config.sql.check_tls()?;
+            config.sql.check_approx()?;
+            config.sql.pool.check()?;
+            config.ldap.check()?;
+            acl::check(&config)?;
+            // Connect in the background, searches fail until the database is reachable
+            let (backend, connected): (Arc<dyn Backend>, BoxFuture<'static, ()>) =
+                match config.sql.backend {
+                    ConfigSqlBackend::PostgreSQL => {
+                        let pool = Arc::new(SqlPool::<sqlx::Postgres>::default());
+                        (
+                            Arc::new(SqlBackend::new(config.clone(), pool.clone())),
+                            Box::pin(sql_pool::connect(config.clone(), db_pass, pool)),
+                        )
+                    }
+                    ConfigSqlBackend::MySQL => {
+                        let pool = Arc::new(SqlPool::<sqlx::MySql>::default());
+                        (
+                            Arc::new(SqlBackend::new(config.clone(), pool.clone())),
+                            Box::pin(sql_pool::connect(config.clone(), db_pass, pool)),
+                        )
+                    }
+                    ConfigSqlBackend::SQLite => {
+                        let pool = Arc::new(SqlPool::<sqlx::Sqlite>::default());
+                        (
+                            Arc::new(SqlBackend::new(config.clone(), pool.clone())),
+                            Box::pin(sql_pool::connect(config.clone(), db_pass, pool)),
+                        )
+                    }
+                };
+            tokio::spawn(async {
+                connected.await;
+                // Apply seccomp filters after db connections where opened
+                SECCOMP_ARMED.store(true, Ordering::Release);
+            });
 
             let listener_tokio = Box::new(TcpListener::from_std(listener).unwrap());
 
             // Initiate the acceptor task.
-            tokio::spawn(acceptor(listener_tokio, config, db_pool));
-
+            tokio::spawn(acceptor(
+                listener_tokio,
+                tls_listener.as_ref().map(|(_, tls, _)| tls.acceptor()),
+                false,
+                config.clone(),
+                backend.clone(),
+            ));
             log::info!("serving ldap://{} ...", addr);
+
+            let tls = match tls_listener {
+                Some((listener, tls, addr)) => {
+                    let listener_tokio = Box::new(TcpListener::from_std(listener).unwrap());
+                    tokio::spawn(acceptor(
+                        listener_tokio,
+                        Some(tls.acceptor()),
+                        true,
+                        config.clone(),
+                        backend,
+                    ));
+                    log::info!("serving ldaps://{} ...", addr);
+
+                    // The filter forbids opening files
+                    if !config.server.seccomp {
+                        let tls = tls.clone();
+                        let config = config.clone();
+                        tokio::spawn(async move {
+                            tls.watch(config.server.tls.as_ref().unwrap()).await
+                        });
+                    }
+                    Some(tls)
+                }
+                None => None,
+            };
+
             if cfg![target_family = "unix"] {
                 use tokio::signal::unix::*;
                 let err_msg = |err| format!("Failed to install signal handler: {}", err);
 
                 let mut int = signal(SignalKind::interrupt()).map_err(err_msg)?;
                 let mut term = signal(SignalKind::terminate()).map_err(err_msg)?;
-                tokio::select! {
-                    _ = int.recv() => {},
-                    _ = term.recv() => {},
+                // SIGHUP reloads the TLS certificate, without TLS it terminates as usual
+                let mut hup = match &tls {
+                    Some(_) => Some(signal(SignalKind::hangup()).map_err(err_msg)?),
+                    None => None,
+                };
+                loop {
+                    tokio::select! {
+                        _ = int.recv() => break,
+                        _ = term.recv() => break,
+                        Some(_) = async { hup.as_mut()?.recv().await } => {
+                            if let (Some(tls), Some(conf)) = (&tls, &config.server.tls) {
+                                tls.reload(conf);
+                            }
+                        }
+                    }
                 }
             } else {
                 tokio::signal::ctrl_c().await.unwrap();
@@ -159,7 +259,7 @@ fn main() -> Result<(), String> {
 }
 
 fn load_command_line() -> ArgMatches {
-    let matches = Command::new(clap::crate_name!())
+    Command::new(clap::crate_name!())
         .version(clap::crate_version!())
         .author(clap::crate_authors!())
         .about("Present relational SQL data 📋 to LDAP clients 🍇")
@@ -184,8 +284,7 @@ fn load_command_line() -> ArgMatches {
                 .help("Prints the program license and exits")
                 .action(ArgAction::SetTrue),
         )
-        .get_matches();
-    matches
+        .get_matches()
 }
 
 fn print_license() {
@@ -231,71 +330,36 @@ fn load_config(config_toml_filename: &str) -> Result<Config, String> {
     })
 }
 
-fn build_pg_connect_options(
-    conf: &Config,
-) -> (
-    sqlx::postgres::PgConnectOptions,
-    sqlx::postgres::PgPoolOptions,
-) {
-    let mut con_opts = sqlx::postgres::PgConnectOptions::new()
-        .username(&conf.sql.user)
-        .password(&conf.sql.pass)
-        .database(&conf.sql.database)
-        .application_name(clap::crate_name!());
-    con_opts = match conf.sql.socket() {
-        Some(socket) => con_opts.socket(socket),
-        None => con_opts.host(&conf.sql.host),
-    };
-    if let Some(port) = conf.sql.port {
-        con_opts = con_opts.port(port);
-    }
-
-    let t = conf.server.threads as u32;
-    let mut pool_opts = sqlx::postgres::PgPoolOptions::new();
-    if conf.server.seccomp {
-        // Can't open a connection when seccomp filter is active
-        pool_opts = pool_opts
-            .max_lifetime(None)
-            .idle_timeout(None)
-            .max_connections(t)
-            .min_connections(t);
-    }
-
-    (con_opts, pool_opts)
-}
-
 fn drop_privileges() -> Result<bool, String> {
-    if cfg!(target_family = "unix") {
-        if unsafe { libc::geteuid() == 0 } {
-            let (uid, gid) = load_uid_gid()?;
-            if unsafe { libc::setgid(gid) != 0 } {
-                Err(format!("setgid({}) failed", gid))?
-            }
-            let default_group = std::ffi::CString::new(DEFAULT_GROUP).unwrap();
-            if unsafe { libc::initgroups(default_group.as_ptr(), gid) != 0 } {
-                Err(format!("initgroups(\"{}\", {}) failed", DEFAULT_GROUP, gid))?
-            }
-
-            let ul_0 = 0 as libc::c_ulong;
-            let ul_1 = 1 as libc::c_ulong;
-            if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, ul_0, ul_0, ul_0, ul_0) } != 0 {
-                Err("prctl(PR_SET_KEEPCAPS, 0) failed".to_owned())?
-            }
-            if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, ul_1, ul_0, ul_0, ul_0) } != 0 {
-                Err("prctl(PR_SET_NO_NEW_PRIVS, 1) failed".to_owned())?
-            }
+    if cfg!(target_family = "unix") && unsafe { libc::geteuid() == 0 } {
+        let (uid, gid) = load_uid_gid()?;
+        if unsafe { libc::setgid(gid) != 0 } {
+            Err(format!("setgid({}) failed", gid))?
+        }
+        let default_group = std::ffi::CString::new(DEFAULT_GROUP).unwrap();
+        if unsafe { libc::initgroups(default_group.as_ptr(), gid) != 0 } {
+            Err(format!("initgroups(\"{}\", {}) failed", DEFAULT_GROUP, gid))?
+        }
 
-            // This will set throw invalid arguments at older kernels:
-            // caps::clear(None, caps::CapSet::Bounding)
-            //     .map_err(|err| format!("Could not clear bounding capabilities: {}", err))?;
-            if unsafe { libc::setuid(uid) == -1 } {
-                Err(format!("setuid({}) failed", uid))?
-            }
-            caps::clear(None, caps::CapSet::Inheritable)
-                .map_err(|err| format!("Could not clear inheritable capabilities: {}", err))?;
+        let ul_0 = 0 as libc::c_ulong;
+        let ul_1 = 1 as libc::c_ulong;
+        if unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, ul_0, ul_0, ul_0, ul_0) } != 0 {
+            Err("prctl(PR_SET_KEEPCAPS, 0) failed".to_owned())?
+        }
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, ul_1, ul_0, ul_0, ul_0) } != 0 {
+            Err("prctl(PR_SET_NO_NEW_PRIVS, 1) failed".to_owned())?
+        }
 
-            return Ok(true);
+        // This will set throw invalid arguments at older kernels:
+        // caps::clear(None, caps::CapSet::Bounding)
+        //     .map_err(|err| format!("Could not clear bounding capabilities: {}", err))?;
+        if unsafe { libc::setuid(uid) == -1 } {
+            Err(format!("setuid({}) failed", uid))?
         }
+        caps::clear(None, caps::CapSet::Inheritable)
+            .map_err(|err| format!("Could not clear inheritable capabilities: {}", err))?;
+
+        return Ok(true);
     }
     Ok(false)
 }
@@ -327,6 +391,8 @@ fn load_uid_gid() -> Result<(libc::uid_t, libc::gid_t), String> {
     target_os = "linux",
     any(target_arch = "x86_64", target_arch = "aarch64")
 ))]
+// The type of libc::TCGETS differs between the gnu and musl targets
+#[allow(clippy::unnecessary_cast)]
 fn build_seccomp_program() -> Result<Vec<BpfProgram>, seccompiler::BackendError> {
     let len_pointer = if cfg!(target_pointer_width = "32") {
         || SeccompCmpArgLen::Dword
@@ -375,7 +441,7 @@ fn build_seccomp_program() -> Result<Vec<BpfProgram>, seccompiler::BackendError>
                 libc::SYS_ioctl,
                 vec![SeccompRule::new(vec![
                     // isatty()
-                    SeccompCondition::new(0, len_long(), SeccompCmpOp::Eq, 1 as u64)?, // fd == stdout
+                    SeccompCondition::new(0, len_long(), SeccompCmpOp::Eq, 1u64)?, // fd == stdout
                     SeccompCondition::new(1, len_long(), SeccompCmpOp::Eq, libc::TCGETS as u64)?,
                 ])?],
             ),
@@ -436,8 +502,10 @@ fn build_seccomp_program() -> Result<Vec<BpfProgram>, seccompiler::BackendError>
 
 async fn acceptor(
     listener: Box<TcpListener>,
+    tls_acceptor: Option<TlsAcceptor>,
+    ldaps: bool,
     config: Arc<Config>,
-    db_pool: Arc<sqlx::postgres::PgPool>,
+    backend: Arc<dyn Backend>,
 ) {
     loop {
         match listener.accept().await {
@@ -445,8 +513,10 @@ async fn acceptor(
                 tokio::spawn(handle_client(
                     socket,
                     paddr,
+                    tls_acceptor.clone(),
+                    ldaps,
                     config.clone(),
-                    db_pool.clone(),
+                    backend.clone(),
                 ));
             }
             Err(_e) => {
@@ -456,64 +526,123 @@ async fn acceptor(
     }
 }
 
+/// Serves a client, over TLS right away for LDAPS or after StartTLS otherwise.
 async fn handle_client(
     socket: TcpStream,
-    _paddr: net::SocketAddr,
+    paddr: net::SocketAddr,
+    tls_acceptor: Option<TlsAcceptor>,
+    ldaps: bool,
     config: Arc<Config>,
-    db_pool: Arc<sqlx::postgres::PgPool>,
+    backend: Arc<dyn Backend>,
 ) {
-    // Configure the codec etc.
-    let (r, w) = tokio::io::split(socket);
-    let mut reqs = FramedRead::new(r, LdapCodec::default());
-    let mut resp = FramedWrite::new(w, LdapCodec::default());
-    let mut session = LdapSession::new(config, db_pool);
+    let mut session = LdapSession::new(config, backend, false, paddr.ip());
+    let socket = if ldaps {
+        socket
+    } else {
+        match serve(Framed::new(socket, LdapCodec), &mut session).await {
+            Some(socket) => socket,
+            // Client disconnected
+            None => return,
+        }
+    };
+
+    // The handshake runs in the client task to not block the listener
+    match tls_acceptor.unwrap().accept(socket).await {
+        Ok(stream) => {
+            session.set_tls();
+            serve(Framed::new(stream, LdapCodec), &mut session).await;
+        }
+        Err(err) => log::debug!("TLS handshake with {} failed: {}", paddr, err),
+    }
+}
 
-    while let Some(msg) = reqs.next().await {
+/// Answers the requests of a client until it disconnects or StartTLS succeeds,
+/// then the stream is returned for the handshake.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    mut framed: Framed<S, LdapCodec>,
+    session: &mut LdapSession,
+) -> Option<S> {
+    while let Some(msg) = framed.next().await {
         // TODO switch to full Op handling
-        let search_sizelimit = match &msg {
+        let (msg, other_controls) = match msg {
+            Ok(Message { msg, controls }) => (Ok(msg), controls),
+            Err(err) => (Err(err), Vec::new()),
+        };
+        let (search_sizelimit, controls) = match &msg {
             Ok(msg) => match &msg.op {
-                ldap3_proto::proto::LdapOp::SearchRequest(req) => req.sizelimit,
-                _ => 0,
+                LdapOp::SearchRequest(req) => (req.sizelimit, msg.ctrl.clone()),
+                _ => (0, Vec::new()),
             },
-            Err(_) => 0,
+            Err(_) => (0, Vec::new()),
         };
 
-        let server_op = match msg
-            .map_err(|_e| ())
-            .and_then(|msg| ServerOps::try_from(msg))
+        // Extended operations ServerOps does not know
+        if let Ok(LdapMsg {
+            msgid,
+            op: LdapOp::ExtendedRequest(req),
+            ..
+        }) = &msg
         {
+            if req.name == STARTTLS_OID {
+                let rmsg = session.do_starttls(*msgid);
+                let upgrade = matches!(&rmsg.op, LdapOp::ExtendedResponse(res)
+                    if res.res.code == LdapResultCode::Success);
+                if framed.send(rmsg.into()).await.is_err() {
+                    return None;
+                }
+                if upgrade {
+                    let parts = framed.into_parts();
+                    // The client must wait for the response before the handshake
+                    return parts.read_buf.is_empty().then_some(parts.io);
+                }
+                continue;
+            } else if req.name != WHOAMI_OID {
+                let rmsg = session.do_unsupported_extended(*msgid, &req.name);
+                if framed.send(rmsg.into()).await.is_err() {
+                    return None;
+                }
+                continue;
+            }
+        }
+
+        let server_op = match msg.map_err(|_e| ()).and_then(ServerOps::try_from) {
             Ok(v) => v,
             Err(_) => {
-                let _err = resp
-                    .send(DisconnectionNotice::gen(
-                        LdapResultCode::Other,
-                        "Internal Server Error",
-                    ))
+                let _err = framed
+                    .send(
+                        DisconnectionNotice::gen(LdapResultCode::Other, "Internal Server Error")
+                            .into(),
+                    )
                     .await;
-                let _err = resp.flush().await;
-                return;
+                let _err = framed.flush().await;
+                return None;
             }
         };
 
-        let result = match server_op {
-            ServerOps::SimpleBind(sbr) => vec![session.do_bind(&sbr).await],
-            ServerOps::Search(sr) => session.do_search(&sr, search_sizelimit).await,
+        let result: Vec<Message> = match server_op {
+            ServerOps::SimpleBind(sbr) => vec![session.do_bind(&sbr).await.into()],
+            ServerOps::Search(sr) => {
+                session
+                    .do_search(&sr, search_sizelimit, &controls, &other_controls)
+                    .await
+            }
             ServerOps::Unbind(_) => {
-                return;
+                return None;
             }
-            ServerOps::Compare(cp) => vec![session.do_compare(&cp)],
-            ServerOps::Whoami(wr) => vec![session.do_whoami(&wr)],
+            ServerOps::Compare(cp) => vec![session.do_compare(&cp).await.into()],
+            ServerOps::Whoami(wr) => vec![session.do_whoami(&wr).into()],
         };
 
         for rmsg in result.into_iter() {
-            if let Err(_) = resp.send(rmsg).await {
-                return;
+            if framed.feed(rmsg).await.is_err() {
+                return None;
             }
         }
 
-        if let Err(_) = resp.flush().await {
-            return;
+        if framed.flush().await.is_err() {
+            return None;
         }
     }
     // Client disconnected
+    None
 }
@@ -0,0 +1,248 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Verification of stored password hashes.
+//!
+//! Supported are `{SSHA}`, SHA-512 and bcrypt crypt(3) hashes (optionally prefixed with
+//! `{CRYPT}`, `{SHA512-CRYPT}` or `{BLF-CRYPT}`), argon2 PHC strings, Django
+//! `pbkdf2_<digest>$...` and PHP `<digest>:<iterations>:[<size>:]<salt>:<hash>` hashes.
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use sha2::{Sha256, Sha512};
+
+/// Checks the password against the hash, fails if the format is not supported.
+pub fn verify(password: &str, hash: &str) -> Result<bool, String> {
+    // RFC 2307 and Dovecot style scheme prefix
+    if let Some((scheme, value)) = hash.strip_prefix('{').and_then(|h| h.split_once('}')) {
+        return match scheme.to_ascii_uppercase().as_str() {
+            "SSHA" => verify_ssha(password, value),
+            "CRYPT" | "SHA512-CRYPT" | "BLF-CRYPT" | "ARGON2I" | "ARGON2ID" => {
+                verify(password, value)
+            }
+            _ => Err(format!("Unsupported password scheme {{{}}}", scheme)),
+        };
+    }
+
+    if hash.starts_with("$6$") {
+        Ok(pwhash::sha512_crypt::verify(password, hash))
+    } else if ["$2a$", "$2b$", "$2y$"].iter().any(|v| hash.starts_with(v)) {
+        Ok(pwhash::bcrypt::verify(password, hash))
+    } else if hash.starts_with("$argon2") {
+        let hash =
+            PasswordHash::new(hash).map_err(|err| format!("Invalid argon2 hash: {}", err))?;
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok())
+    } else if let Some(hash) = hash.strip_prefix("pbkdf2_") {
+        verify_django(password, hash)
+    } else if hash.split(':').count() >= 4 {
+        verify_php(password, hash)
+    } else {
+        Err("Unknown password hash format".to_owned())
+    }
+}
+
+/// Salted SHA-1, the salt is appended to the digest.
+fn verify_ssha(password: &str, value: &str) -> Result<bool, String> {
+    let decoded = decode(value)?;
+    if decoded.len() <= 20 {
+        return Err("Invalid {SSHA} hash".to_owned());
+    }
+    let (digest, salt) = decoded.split_at(20);
+    let mut hasher = Sha1::new();
+    hasher.update(password.as_bytes());
+    hasher.update(salt);
+    Ok(constant_time_eq(&hasher.finalize(), digest))
+}
+
+/// `<digest>$<iterations>$<salt>$<hash>`
+fn verify_django(password: &str, hash: &str) -> Result<bool, String> {
+    let invalid = || "Invalid Django pbkdf2 hash".to_owned();
+    let parts: Vec<&str> = hash.split('$').collect();
+    let [digest, iterations, salt, expected] = parts[..] else {
+        return Err(invalid());
+    };
+    let iterations = iterations.parse().map_err(|_| invalid())?;
+    verify_pbkdf2(
+        digest,
+        password,
+        salt.as_bytes(),
+        iterations,
+        &decode(expected)?,
+    )
+}
+
+/// The format of the defuse/password-hashing library, version 1 uses the encoded salt
+/// as is, version 2 adds the output size and decodes the salt.
+fn verify_php(password: &str, hash: &str) -> Result<bool, String> {
+    let invalid = || "Invalid PHP pbkdf2 hash".to_owned();
+    let parts: Vec<&str> = hash.split(':').collect();
+    let (digest, iterations, salt, expected) = match parts[..] {
+        [digest, iterations, salt, expected] => {
+            (digest, iterations, salt.as_bytes().to_vec(), expected)
+        }
+        [digest, iterations, _, salt, expected] => (digest, iterations, decode(salt)?, expected),
+        _ => return Err(invalid()),
+    };
+    let iterations = iterations.parse().map_err(|_| invalid())?;
+    verify_pbkdf2(digest, password, &salt, iterations, &decode(expected)?)
+}
+
+fn verify_pbkdf2(
+    digest: &str,
+    password: &str,
+    salt: &[u8],
+    iterations: u32,
+    expected: &[u8],
+) -> Result<bool, String> {
+    if expected.is_empty() || iterations == 0 {
+        return Err("Invalid pbkdf2 hash".to_owned());
+    }
+    let mut out = vec![0; expected.len()];
+    let password = password.as_bytes();
+    match digest {
+        "sha1" => pbkdf2::pbkdf2_hmac::<Sha1>(password, salt, iterations, &mut out),
+        "sha256" => pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut out),
+        "sha512" => pbkdf2::pbkdf2_hmac::<Sha512>(password, salt, iterations, &mut out),
+        _ => return Err(format!("Unsupported pbkdf2 digest {}", digest)),
+    }
+    Ok(constant_time_eq(&out, expected))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, String> {
+    BASE64
+        .decode(value)
+        .map_err(|err| format!("Invalid base64 in password hash: {}", err))
+}
+
+/// Does not return early, so the time taken does not reveal the matching prefix.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+
+    #[test]
+    fn ssha() {
+        let hash = "{SSHA}QxWRhccNco81WkE5NvgyzxlVKSQBAgMEc2FsdA==";
+        assert_eq!(verify("secret", hash), Ok(true));
+        assert_eq!(verify("Secret", hash), Ok(false));
+        // The digest alone has no salt
+        assert!(verify("secret", "{SSHA}QxWRhccNco81WkE5NvgyzxlVKSQ=").is_err());
+        assert!(verify("secret", "{SSHA}not base64!").is_err());
+    }
+
+    #[test]
+    fn sha512_crypt() {
+        let hash = "$6$saltsalt$TVLlQcbpFVof5W3Yz4DTP6gRstiNuHwwTt6GLc1E5n0U0aDehy0S5knV8wiOQSpT0Y77vwPZN.Pq.H91p5hVO1";
+        assert_eq!(verify("secret", hash), Ok(true));
+        assert_eq!(
+            verify("secret", &format!("{{SHA512-CRYPT}}{}", hash)),
+            Ok(true)
+        );
+        assert_eq!(verify("secret", &format!("{{CRYPT}}{}", hash)), Ok(true));
+        assert_eq!(verify("secret!", hash), Ok(false));
+        assert_eq!(
+            verify("secret", "{SHA512-CRYPT}$6$saltsalt$TVLlQcbp"),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn bcrypt() {
+        for hash in [
+            "$2a$04$abcdefghijklmnopqrstuujydOTSfIH/d5oUHpsygqV5X9xJLQc6e",
+            "$2b$05$abcdefghijklmnopqrstuuHNbAKRhpaujgo33bRWs.NLUTJO3lOy2",
+            "$2y$04$abcdefghijklmnopqrstuujydOTSfIH/d5oUHpsygqV5X9xJLQc6e",
+        ] {
+            assert_eq!(verify("correct horse", hash), Ok(true), "{}", hash);
+            assert_eq!(
+                verify("correct horse", &format!("{{BLF-CRYPT}}{}", hash)),
+                Ok(true)
+            );
+            assert_eq!(verify("correct  horse", hash), Ok(false), "{}", hash);
+        }
+        assert_eq!(
+            verify("correct horse", "$2b$04$abcdefghijklmnopqrstuu"),
+            Ok(false)
+        );
+        assert_eq!(verify("correct horse", "$2b$xx$"), Ok(false));
+    }
+
+    #[test]
+    fn argon2id() {
+        // Test vector of the reference implementation
+        let hash =
+            "$argon2id$v=19$m=256,t=2,p=1$c29tZXNhbHQ$nf65EOgLrQMR/uIPnA4rEsF5h7TKyQwu9U1bMCHGi/4";
+        assert_eq!(verify("password", hash), Ok(true));
+        assert_eq!(
+            verify("password", &format!("{{ARGON2ID}}{}", hash)),
+            Ok(true)
+        );
+        assert_eq!(verify("Password", hash), Ok(false));
+        // Parses without the salt and hash, but never matches
+        assert_eq!(
+            verify("password", "$argon2id$v=19$m=256,t=2,p=1"),
+            Ok(false)
+        );
+        assert!(verify(
+            "password",
+            "$argon2id$v=19$m=x,t=2,p=1$c29tZXNhbHQ$nf65EOgLrQMR"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn django_pbkdf2() {
+        let hash = "pbkdf2_sha256$1000$saltsalt$hgR9HsqtKupWxpnv8y99TrPDajTT/9PcSTlNafpdLXQ=";
+        assert_eq!(verify("secret", hash), Ok(true));
+        assert_eq!(verify("secrets", hash), Ok(false));
+        assert!(verify("secret", "pbkdf2_sha256$1000$saltsalt").is_err());
+        assert!(verify("secret", "pbkdf2_sha256$many$saltsalt$hgR9HsqtKupW").is_err());
+        assert!(verify("secret", "pbkdf2_sha256$0$saltsalt$hgR9HsqtKupW").is_err());
+        assert!(verify("secret", "pbkdf2_md5$1000$saltsalt$hgR9HsqtKupW").is_err());
+    }
+
+    #[test]
+    fn php_pbkdf2() {
+        // Version 1 uses the encoded salt
+        let hash = "sha1:1000:c2FsdA==:S1tSSsvkLDTKfD3fEV62LF6Lq48/ZkpR";
+        assert_eq!(verify("secret", hash), Ok(true));
+        assert_eq!(verify("Secret", hash), Ok(false));
+        let hash = "sha512:1000:salt:929yOBt18N6xwzkzSoyJdDZsrbxr9GRg+Xg2PejSENs=";
+        assert_eq!(verify("secret", hash), Ok(true));
+        assert_eq!(verify("Secret", hash), Ok(false));
+        // Version 2 decodes the salt
+        let hash = "sha256:1000:24:cmF3c2FsdCE=:6A3jztQKcWJIa9d9nRUKVr+WZV2om4v7";
+        assert_eq!(verify("secret", hash), Ok(true));
+        assert_eq!(verify("Secret", hash), Ok(false));
+
+        assert!(verify("secret", "sha256:1000:salt:").is_err());
+        assert!(verify("secret", "sha256:1000:24:not base64!:6A3jztQK").is_err());
+        assert!(verify("secret", "sha256:1000:24:salt:hash:extra").is_err());
+        assert!(verify("secret", "md5:1000:salt:6A3jztQK").is_err());
+    }
+
+    #[test]
+    fn unknown_formats() {
+        assert!(verify("secret", "secret").is_err());
+        assert!(verify("secret", "{MD5}Xr4ilOzQ4PCOq3aQ0qbuaQ==").is_err());
+        assert!(verify("secret", "$1$saltsalt$qjXMvbEw8oaL.CzflDugX/").is_err());
+    }
+}
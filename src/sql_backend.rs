@@ -0,0 +1,814 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+
+use async_stream::try_stream;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use ldap3_proto::proto::{LdapFilter, LdapMatchingRuleAssertion};
+use sqlx::Row;
+
+use crate::acl::Access;
+use crate::backend::*;
+use crate::cologne;
+use crate::config::*;
+use crate::sql_pool::SqlPool;
+use crate::sql_query::*;
+
+/// Backend answering searches from a table of an SQL database.
+pub struct SqlBackend<DB: sqlx::Database> {
+    conf: Arc<Config>,
+    dialect: &'static dyn Dialect,
+    pool: Arc<SqlPool<DB>>,
+}
+
+impl<DB: sqlx::Database> SqlBackend<DB> {
+    pub fn new(conf: Arc<Config>, pool: Arc<SqlPool<DB>>) -> Self {
+        Self {
+            dialect: dialect(conf.sql.backend),
+            conf,
+            pool,
+        }
+    }
+
+    fn build_query(&self, search: &Search) -> Select {
+        let mappings = &self.conf.mappings;
+        let (_, _, cn_col) = mappings.get("cn").unwrap();
+
+        // Just hit the db with the requested attributes the identity may read
+        let mut columns: Vec<(Column, String)> = search
+            .attributes
+            .iter()
+            .filter(|attr_lower| search.access.can_read(attr_lower))
+            .filter_map(|attr_lower| mappings.get(attr_lower))
+            .map(|(attr_lower, _, col)| (Column::Expr(col.to_owned()), attr_lower.to_owned()))
+            .collect();
+        if !columns.iter().any(|(_, alias)| alias == "cn") {
+            // cn is always required to build the dn
+            columns.push((Column::Expr(cn_col.to_owned()), "cn".to_owned()));
+        }
+
+        let mut order_by: Vec<Order> = search
+            .sort
+            .iter()
+            .filter_map(|sort| {
+                let (_, _, col) = mappings.get(sort.attribute)?;
+                Some(Order::IgnoreCase {
+                    expr: col.to_owned(),
+                    reverse: sort.reverse,
+                })
+            })
+            .collect();
+        if !order_by.is_empty() || search.offset.is_some() {
+            // The cn makes the order deterministic
+            order_by.push(Order::Expr(cn_col.to_owned()));
+        }
+
+        Select {
+            columns,
+            table: self.conf.sql.table.to_owned(),
+            filter: Some(self.build_filter(search)),
+            order_by,
+            limit: (search.size_limit > 0).then_some(search.size_limit as u32),
+            offset: search.offset.filter(|&offset| offset > 0),
+        }
+    }
+
+    fn build_count_query(&self, search: &Search, before: Option<&str>) -> Select {
+        let mut filter = self.build_filter(search);
+        if let (Some(value), Some(sort)) = (before, search.sort.first()) {
+            let (_, _, col) = self.conf.mappings.get(sort.attribute).unwrap();
+            filter = Condition::and(vec![
+                filter,
+                Condition::SortsBefore {
+                    expr: col.to_owned(),
+                    value: value.to_owned(),
+                    reverse: sort.reverse,
+                },
+            ]);
+        }
+        Select {
+            columns: vec![(Column::Expr("COUNT(*)".to_owned()), "count".to_owned())],
+            table: self.conf.sql.table.to_owned(),
+            filter: Some(filter),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+        }
+    }
+
+    /// The condition for the entries of the search that the identity may see.
+    fn build_filter(&self, search: &Search) -> Condition {
+        let mappings = &self.conf.mappings;
+        let filter = match search.target {
+            // Base scope, return just one object
+            SearchTarget::Cn(cn) => {
+                let (_, _, cn_col) = mappings.get("cn").unwrap();
+                Condition::EqualsIgnoreCase(cn_col.to_owned(), cn.to_owned())
+            }
+            // Search the complete dn
+            SearchTarget::Filter(filter) => {
+                compile_filter(&self.conf, search.access, filter).matching()
+            }
+        };
+        restrict_rows(filter, search.access)
+    }
+
+    fn build_credentials_query(&self, cn: &str) -> Option<Select> {
+        let password_col = self.conf.sql.password_column.as_ref()?;
+        let (_, _, cn_col) = self.conf.mappings.get("cn").unwrap();
+        Some(self.build_entry_query(
+            cn,
+            vec![
+                (Column::Expr(cn_col.to_owned()), "cn".to_owned()),
+                (Column::Expr(password_col.to_owned()), "password".to_owned()),
+            ],
+            None,
+        ))
+    }
+
+    fn build_compare_query(&self, compare: &Compare) -> Select {
+        let (_, _, col) = self.conf.mappings.get(compare.attribute).unwrap();
+        // Same semantics as the equality filter
+        let matches = Condition::EqualsIgnoreCase(col.to_owned(), compare.value.to_owned());
+        self.build_entry_query(
+            compare.cn,
+            vec![(Column::Matches(matches), "matches".to_owned())],
+            Some(compare.access),
+        )
+    }
+
+    /// Query for a single entry, which is looked up case insensitively like in filters.
+    fn build_entry_query(
+        &self,
+        cn: &str,
+        columns: Vec<(Column, String)>,
+        access: Option<&Access>,
+    ) -> Select {
+        let (_, _, cn_col) = self.conf.mappings.get("cn").unwrap();
+        let filter = Condition::EqualsIgnoreCase(cn_col.to_owned(), cn.to_owned());
+        Select {
+            columns,
+            table: self.conf.sql.table.to_owned(),
+            filter: Some(match access {
+                Some(access) => restrict_rows(filter, access),
+                None => filter,
+            }),
+            order_by: Vec::new(),
+            // A second row makes the cn ambiguous
+            limit: Some(2),
+            offset: None,
+        }
+    }
+
+    fn render(&self, select: &Select) -> (String, Vec<String>) {
+        let (query, bindings) = select.render(self.dialect);
+        if log::log_enabled!(log::Level::Debug) {
+            log::debug!("Query: {}", query);
+            if !bindings.is_empty() {
+                log::debug!("Params: \"{}\"", bindings.join("\", \""));
+            }
+        }
+        (query, bindings)
+    }
+
+    /// Logs the error together with the query, lost connections make the pool check
+    /// the database host.
+    fn query_failed(&self, query: &str, err: sqlx::Error) -> BackendError {
+        log::error!("{} in query: {}", err, query);
+        self.pool.failed(&err);
+        BackendError::Sql(err)
+    }
+}
+
+impl<DB> SqlBackend<DB>
+where
+    DB: sqlx::Database,
+    for<'q> <DB as sqlx::database::HasArguments<'q>>::Arguments: sqlx::IntoArguments<'q, DB> + Send,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'q> String: sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+{
+    /// Fetches and decodes the row of a single entry query, if there is exactly one.
+    async fn fetch_entry<T>(
+        &self,
+        select: &Select,
+        cn: &str,
+        decode: impl FnOnce(&DB::Row) -> Result<T, sqlx::Error>,
+    ) -> Result<Option<T>, BackendError> {
+        let pool = self.pool.get()?;
+        let (query, bindings) = self.render(select);
+        let mut q = sqlx::query::<DB>(&query);
+        for b in bindings {
+            q = q.bind(b);
+        }
+        let mut rows = q
+            .fetch_all(&pool)
+            .await
+            .map_err(|err| self.query_failed(&query, err))?;
+        if rows.len() > 1 {
+            log::warn!("cn={} matches multiple rows", cn);
+            return Ok(None);
+        }
+        match rows.pop() {
+            Some(row) => decode(&row)
+                .map(Some)
+                .map_err(|err| self.query_failed(&query, err)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<DB> Backend for SqlBackend<DB>
+where
+    DB: sqlx::Database,
+    for<'q> <DB as sqlx::database::HasArguments<'q>>::Arguments: sqlx::IntoArguments<'q, DB> + Send,
+    for<'c> &'c mut DB::Connection: sqlx::Executor<'c, Database = DB>,
+    for<'q> String: sqlx::Encode<'q, DB> + sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'q> i64: sqlx::Decode<'q, DB> + sqlx::Type<DB>,
+    for<'a> &'a str: sqlx::ColumnIndex<DB::Row>,
+{
+    fn search<'a>(&'a self, search: &'a Search<'a>) -> BoxStream<'a, Result<Entry, BackendError>> {
+        Box::pin(try_stream! {
+            let pool = self.pool.get()?;
+            let (query, bindings) = self.render(&self.build_query(search));
+
+            let failed = |err: sqlx::Error| self.query_failed(&query, err);
+
+            let mut rows = {
+                let mut q = sqlx::query::<DB>(&query);
+                for b in bindings {
+                    q = q.bind(b);
+                }
+                q.fetch(&pool)
+            };
+
+            while let Some(row) = rows.try_next().await.map_err(failed)? {
+                let mut values = Vec::with_capacity(search.attributes.len());
+                for attr in &search.attributes {
+                    values.push(if search.access.can_read(attr) {
+                        row.try_get(*attr).map_err(failed)?
+                    } else {
+                        None
+                    });
+                }
+                yield Entry {
+                    cn: row.try_get("cn").map_err(failed)?,
+                    values,
+                };
+            }
+        })
+    }
+
+    fn count<'a>(
+        &'a self,
+        search: &'a Search<'a>,
+        before: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u32, BackendError>> {
+        Box::pin(async move {
+            let pool = self.pool.get()?;
+            let (query, bindings) = self.render(&self.build_count_query(search, before));
+            let mut q = sqlx::query::<DB>(&query);
+            for b in bindings {
+                q = q.bind(b);
+            }
+            let row = q
+                .fetch_one(&pool)
+                .await
+                .map_err(|err| self.query_failed(&query, err))?;
+            let count: i64 = row
+                .try_get("count")
+                .map_err(|err| self.query_failed(&query, err))?;
+            Ok(count.try_into().unwrap_or(u32::MAX))
+        })
+    }
+
+    fn credentials<'a>(
+        &'a self,
+        cn: &'a str,
+    ) -> BoxFuture<'a, Result<Option<Credentials>, BackendError>> {
+        Box::pin(async move {
+            let select = match self.build_credentials_query(cn) {
+                Some(select) => select,
+                None => return Ok(None),
+            };
+            let credentials = self.fetch_entry(&select, cn, |row| {
+                let password: Option<String> = row.try_get("password")?;
+                Ok(match password.filter(|p| !p.is_empty()) {
+                    Some(password) => Some(Credentials {
+                        cn: row.try_get("cn")?,
+                        password,
+                    }),
+                    None => None,
+                })
+            });
+            Ok(credentials.await?.flatten())
+        })
+    }
+
+    fn compare<'a>(
+        &'a self,
+        compare: &'a Compare<'a>,
+    ) -> BoxFuture<'a, Result<Option<bool>, BackendError>> {
+        Box::pin(async move {
+            let select = self.build_compare_query(compare);
+            self.fetch_entry(&select, compare.cn, |row| {
+                Ok(row.try_get::<String, _>("matches")? == "TRUE")
+            })
+            .await
+        })
+    }
+}
+
+/// Limits the condition to the rows the identity may see.
+pub fn restrict_rows(condition: Condition, access: &Access) -> Condition {
+    match &access.rows {
+        Some(rows) => Condition::and(vec![condition, Condition::Sql(rows.to_owned())]),
+        None => condition,
+    }
+}
+
+/// Translates the LDAP filter recursively.
+///
+/// Assertions on attributes that are not mapped and assertion values that are invalid for
+/// the attribute are Undefined.
+pub fn compile_filter(conf: &Config, access: &Access, filter: &LdapFilter) -> Condition {
+    let mappings = &conf.mappings;
+    // Attributes the identity may not search on behave like unmapped ones
+    let get_mapping = |attr: &str| match mappings.get(attr) {
+        Some((attr_lower, _, col)) if access.can_search(attr_lower) => Some(col.to_owned()),
+        _ => None,
+    };
+    let compile_all = |filters: &[LdapFilter]| {
+        filters
+            .iter()
+            .map(|f| compile_filter(conf, access, f))
+            .collect()
+    };
+
+    match filter {
+        LdapFilter::And(filters) => Condition::and(compile_all(filters)),
+        LdapFilter::Or(filters) => Condition::or(compile_all(filters)),
+        LdapFilter::Not(filter) => Condition::not(compile_filter(conf, access, filter)),
+        LdapFilter::Equality(attr, value) => get_mapping(attr)
+            .map_or(Condition::Undefined, |col| {
+                Condition::EqualsIgnoreCase(col, value.to_owned())
+            }),
+        LdapFilter::Substring(attr, filter) => {
+            let mut pattern = Vec::with_capacity(filter.any.len() * 2 + 3);
+            if let Some(initial) = &filter.initial {
+                pattern.push(Pattern::Literal(initial.to_owned()));
+            }
+            pattern.push(Pattern::Any);
+            for s in &filter.any {
+                pattern.push(Pattern::Literal(s.to_owned()));
+                pattern.push(Pattern::Any);
+            }
+            if let Some(final_) = &filter.final_ {
+                pattern.push(Pattern::Literal(final_.to_owned()));
+            }
+            get_mapping(attr).map_or(Condition::Undefined, |col| Condition::Like(col, pattern))
+        }
+        LdapFilter::GreaterOrEqual(attr, value) => {
+            match (
+                get_mapping(attr),
+                typed_value(mappings.value_type(attr), value),
+            ) {
+                (Some(col), Some(value)) => Condition::GreaterOrEqual(col, value),
+                _ => Condition::Undefined,
+            }
+        }
+        LdapFilter::LessOrEqual(attr, value) => {
+            match (
+                get_mapping(attr),
+                typed_value(mappings.value_type(attr), value),
+            ) {
+                (Some(col), Some(value)) => Condition::LessOrEqual(col, value),
+                _ => Condition::Undefined,
+            }
+        }
+        LdapFilter::Approx(attr, value) => {
+            let col = match get_mapping(attr) {
+                Some(col) => col,
+                None => return Condition::Undefined,
+            };
+            let value = value.to_owned();
+            match conf.sql.approx {
+                ConfigSqlApprox::Equality => Condition::EqualsIgnoreCase(col, value),
+                ConfigSqlApprox::Trigram => Condition::Approx(col, Approx::Trigram(value)),
+                ConfigSqlApprox::Soundex => Condition::Approx(col, Approx::Soundex(value)),
+                ConfigSqlApprox::Dmetaphone => Condition::Approx(col, Approx::Dmetaphone(value)),
+                ConfigSqlApprox::Cologne => match cologne::encode(&value) {
+                    // Values without letters would match empty ones
+                    code if code.is_empty() => Condition::Undefined,
+                    code => Condition::Approx(col, Approx::Cologne(code)),
+                },
+            }
+        }
+        LdapFilter::Extensible(assertion) => compile_extensible(conf, access, assertion),
+        // Attributes that are not mapped are known to be absent
+        LdapFilter::Present(attr) => {
+            get_mapping(attr).map_or(Condition::Or(Vec::new()), Condition::Present)
+        }
+    }
+}
+
+/// Translates an extensible filter, which is Undefined if the rule is unknown or does not
+/// apply to the attribute.
+fn compile_extensible(
+    conf: &Config,
+    access: &Access,
+    assertion: &LdapMatchingRuleAssertion,
+) -> Condition {
+    let mappings = &conf.mappings;
+    // Without a rule the equality rule of the attribute applies, which is always caseIgnore
+    let rule = match assertion.matching_rule.as_deref().map(matching_rule) {
+        Some(Some(rule)) => rule,
+        None if assertion.type_.is_some() => MatchingRule::CaseIgnore,
+        _ => return Condition::Undefined,
+    };
+    let value = match normalize(rule, &assertion.match_value) {
+        Some(value) => value,
+        None => return Condition::Undefined,
+    };
+    // Integers are the values of the mappings with type number
+    let applies = |attr: &str| {
+        rule != MatchingRule::Integer || mappings.value_type(attr) == ConfigMappingType::Number
+    };
+    let is_type = |attr: &str| {
+        assertion
+            .type_
+            .as_ref()
+            .is_none_or(|t| t.eq_ignore_ascii_case(attr))
+    };
+
+    // Without a type, attributes the identity may not search are left out like unmapped ones
+    let mut cols: Vec<(&str, &str)> = mappings
+        .iter()
+        .filter(|&(attr_lower, _, _)| {
+            is_type(attr_lower) && applies(attr_lower) && access.can_search(attr_lower)
+        })
+        .map(|(attr_lower, _, col)| (attr_lower, col))
+        .collect();
+    let mut undefined = assertion.type_.is_some() && cols.is_empty();
+    if assertion.dn_attributes {
+        // The cn of the DN is visible even if the attribute may not be searched
+        if is_type("cn") && applies("cn") && !cols.iter().any(|&(attr, _)| attr == "cn") {
+            cols.push(("cn", mappings.get("cn").unwrap().2));
+        }
+        // The other attributes of the DN are the same for all entries
+        for (attr, attr_value) in suffix_attributes(&conf.ldap.suffix) {
+            if is_type(attr) {
+                if rule_matches(rule, attr_value, &value) {
+                    return Condition::And(Vec::new());
+                }
+                undefined = false;
+            }
+        }
+    }
+    // Sorted for reproducible queries
+    cols.sort_unstable();
+    let mut conditions: Vec<Condition> = cols
+        .into_iter()
+        .map(|(_, col)| Condition::Rule(col.to_owned(), rule, value.clone()))
+        .collect();
+    if undefined {
+        conditions.push(Condition::Undefined);
+    }
+    Condition::or(conditions)
+}
+
+/// The rules by name or OID.
+fn matching_rule(rule: &str) -> Option<MatchingRule> {
+    Some(match rule.to_ascii_lowercase().as_str() {
+        "caseignorematch" | "2.5.13.2" => MatchingRule::CaseIgnore,
+        "caseexactmatch" | "2.5.13.5" => MatchingRule::CaseExact,
+        "numericstringmatch" | "2.5.13.8" => MatchingRule::NumericString,
+        "telephonenumbermatch" | "2.5.13.20" => MatchingRule::TelephoneNumber,
+        "integermatch" | "2.5.13.14" => MatchingRule::Integer,
+        _ => return None,
+    })
+}
+
+/// Removes the characters the rule ignores, `None` if the value is invalid for the rule.
+fn normalize(rule: MatchingRule, value: &str) -> Option<String> {
+    match rule {
+        MatchingRule::CaseIgnore | MatchingRule::CaseExact => Some(value.to_owned()),
+        MatchingRule::NumericString => value
+            .bytes()
+            .all(|b| b.is_ascii_digit() || b == b' ')
+            .then(|| value.replace(' ', "")),
+        MatchingRule::TelephoneNumber => Some(value.replace([' ', '-'], "")),
+        MatchingRule::Integer => value.parse::<i128>().ok().map(|n| n.to_string()),
+    }
+}
+
+/// Evaluates the rule for a value of the DN with a normalized assertion value.
+fn rule_matches(rule: MatchingRule, value: &str, normalized: &str) -> bool {
+    match (rule, normalize(rule, value)) {
+        (MatchingRule::CaseIgnore | MatchingRule::TelephoneNumber, Some(value)) => {
+            value.to_lowercase() == normalized.to_lowercase()
+        }
+        (_, Some(value)) => value == normalized,
+        (_, None) => false,
+    }
+}
+
+/// The attribute types and values of the suffix, which does not use escapes.
+fn suffix_attributes(suffix: &str) -> impl Iterator<Item = (&str, &str)> {
+    suffix
+        .split([',', '+'])
+        .filter_map(|ava| ava.split_once('='))
+        .map(|(attr, value)| (attr.trim(), value.trim()))
+}
+
+/// Validates the assertion value of an ordering filter.
+fn typed_value(value_type: ConfigMappingType, value: &str) -> Option<Typed> {
+    match value_type {
+        ConfigMappingType::Text => Some(Typed::Text(value.to_owned())),
+        ConfigMappingType::Number => parse_number(value).map(Typed::Number),
+        ConfigMappingType::Timestamp => parse_generalized_time(value).map(Typed::Timestamp),
+    }
+}
+
+/// A decimal number with an optional sign and fraction.
+fn parse_number(value: &str) -> Option<String> {
+    let digits = value.strip_prefix(['+', '-']).unwrap_or(value);
+    let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if int.is_empty() || !is_digits(int) || !is_digits(frac) || digits.ends_with('.') {
+        return None;
+    }
+    Some(value.strip_prefix('+').unwrap_or(value).to_owned())
+}
+
+/// Converts a GeneralizedTime (RFC 4517) to `YYYY-MM-DD HH:MM:SS.ffffff` in UTC.
+///
+/// Fractions of hours and minutes are not supported.
+fn parse_generalized_time(value: &str) -> Option<String> {
+    let (time, offset) = match value.find(['Z', '+', '-']) {
+        Some(i) => value.split_at(i),
+        None => return None,
+    };
+    let (time, fraction) = time.split_once(['.', ',']).unwrap_or((time, ""));
+    if !time.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+        || !matches!(time.len(), 10 | 12 | 14)
+        || (!fraction.is_empty() && time.len() != 14)
+        || (fraction.is_empty() && value.contains(['.', ',']))
+    {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| time.get(range).map_or(Some(0), |s| s.parse().ok());
+    let (year, month, day) = (num(0..4)?, num(4..6)?, num(6..8)?);
+    let (hour, minute, second) = (num(8..10)?, num(10..12)?, num(12..14)?);
+    // Leap seconds are accepted by the syntax but not by the databases
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    let offset = match offset {
+        "Z" => 0,
+        _ => {
+            let digits = &offset[1..];
+            if !matches!(digits.len(), 2 | 4) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let (hours, minutes): (i64, i64) = (
+                digits[..2].parse().ok()?,
+                // The minutes are optional
+                match digits.len() {
+                    4 => digits[2..].parse().ok()?,
+                    _ => 0,
+                },
+            );
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            if offset.starts_with('-') {
+                -(hours * 60 + minutes)
+            } else {
+                hours * 60 + minutes
+            }
+        }
+    };
+
+    let minutes = (days_from_civil(year, month, day) * 24 + hour) * 60 + minute - offset;
+    let (year, month, day) = civil_from_days(minutes.div_euclid(24 * 60));
+    let minutes = minutes.rem_euclid(24 * 60);
+    // Up to microseconds, which all databases store
+    let mut fraction = fraction.to_owned();
+    fraction.truncate(6);
+    while fraction.len() < 6 {
+        fraction.push('0');
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{}",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60,
+        second,
+        fraction
+    ))
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number() {
+        assert_eq!(parse_number("42").as_deref(), Some("42"));
+        assert_eq!(parse_number("+1.5").as_deref(), Some("1.5"));
+        assert_eq!(parse_number("-0.25").as_deref(), Some("-0.25"));
+        assert_eq!(parse_number("007").as_deref(), Some("007"));
+        for invalid in [
+            "", "+", "-", "1.", ".5", "1.2.3", "--1", "+-1", " 1", "1e3", "0x10",
+        ] {
+            assert_eq!(parse_number(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn generalized_time() {
+        let parse = parse_generalized_time;
+        assert_eq!(
+            parse("20240131120000Z").as_deref(),
+            Some("2024-01-31 12:00:00.000000")
+        );
+        assert_eq!(
+            parse("202401311230Z").as_deref(),
+            Some("2024-01-31 12:30:00.000000")
+        );
+        assert_eq!(
+            parse("2024013112Z").as_deref(),
+            Some("2024-01-31 12:00:00.000000")
+        );
+        assert_eq!(
+            parse("00010101000000Z").as_deref(),
+            Some("0001-01-01 00:00:00.000000")
+        );
+        for invalid in [
+            "",
+            "Z",
+            "2024Z",
+            "20240131Z",
+            "2024013112",
+            "20240131120000",
+            "2024013112000Z",
+        ] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn generalized_time_fraction() {
+        let parse = parse_generalized_time;
+        assert_eq!(
+            parse("20240131120000.5Z").as_deref(),
+            Some("2024-01-31 12:00:00.500000")
+        );
+        // Truncated to microseconds
+        assert_eq!(
+            parse("20240131120000,1234567Z").as_deref(),
+            Some("2024-01-31 12:00:00.123456")
+        );
+        // Fractions of hours and minutes
+        assert_eq!(parse("202401311230.5Z"), None);
+        assert_eq!(parse("2024013112.5Z"), None);
+        assert_eq!(parse("20240131120000.Z"), None);
+        assert_eq!(parse("20240131120000.5x0Z"), None);
+    }
+
+    #[test]
+    fn generalized_time_offset() {
+        let parse = parse_generalized_time;
+        assert_eq!(
+            parse("20240101003000+0100").as_deref(),
+            Some("2023-12-31 23:30:00.000000")
+        );
+        assert_eq!(
+            parse("20231231233000-0130").as_deref(),
+            Some("2024-01-01 01:00:00.000000")
+        );
+        assert_eq!(
+            parse("2024013112+02").as_deref(),
+            Some("2024-01-31 10:00:00.000000")
+        );
+        assert_eq!(
+            parse("20240301003000.25+0100").as_deref(),
+            Some("2024-02-29 23:30:00.250000")
+        );
+        assert_eq!(
+            parse("00010101000000+0100").as_deref(),
+            Some("0000-12-31 23:00:00.000000")
+        );
+        for invalid in [
+            "+2400", "+0060", "+1", "+013", "+01:00", "+0100Z", "Z+0100", "z",
+        ] {
+            let value = format!("20240131120000{}", invalid);
+            assert_eq!(parse(&value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn generalized_time_calendar() {
+        let parse = parse_generalized_time;
+        assert!(parse("20240229120000Z").is_some());
+        assert!(parse("20000229120000Z").is_some());
+        assert!(parse("20241231235959Z").is_some());
+        for invalid in [
+            "20230229120000Z",
+            "19000229120000Z",
+            "20240431120000Z",
+            "20240001120000Z",
+            "20241301120000Z",
+            "20240100120000Z",
+            "20240132120000Z",
+            "20240131240000Z",
+            "20240131126000Z",
+            // Leap seconds
+            "20161231235960Z",
+        ] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2024, 2, 29), 19782);
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-719528), (0, 1, 1));
+
+        // Consecutive days from year 0 to beyond 2400
+        let mut date = (0, 1, 1);
+        for days in -719528..160000 {
+            assert_eq!(civil_from_days(days), date);
+            assert_eq!(days_from_civil(date.0, date.1, date.2), days);
+            date = if date.2 < days_in_month(date.0, date.1) {
+                (date.0, date.1, date.2 + 1)
+            } else if date.1 < 12 {
+                (date.0, date.1 + 1, 1)
+            } else {
+                (date.0 + 1, 1, 1)
+            };
+        }
+    }
+}
@@ -0,0 +1,567 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Connection pools to the configured database.
+
+use std::ffi::{c_char, c_int};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+use sqlx::Connection;
+use tokio::sync::Notify;
+
+use crate::backend::BackendError;
+use crate::cologne;
+use crate::config::*;
+
+/// How often the password file and the database host are checked
+const MONITOR_INTERVAL: Duration = Duration::from_secs(10);
+/// Time a database host has to accept and answer a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay of the first reconnect, doubled after each failure up to the maximum
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+pub type ConnectOptions<DB> = <<DB as sqlx::Database>::Connection as Connection>::Options;
+
+/// A database sql2ldap can connect to.
+pub trait Driver: sqlx::Database {
+    fn connect_options(conf: &Config, host: &SqlHost, pass: &str) -> ConnectOptions<Self>;
+
+    /// Checks the server behind a connection is one the config asks for.
+    fn check_server<'c>(
+        conf: &'c Config,
+        host: SqlHost<'c>,
+        conn: &'c mut Self::Connection,
+    ) -> BoxFuture<'c, Result<(), String>>;
+
+    /// Prepares each connection of the pool before it is used.
+    fn prepare(
+        _approx: ConfigSqlApprox,
+        _conn: &mut Self::Connection,
+    ) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Driver for sqlx::Postgres {
+    fn connect_options(conf: &Config, host: &SqlHost, pass: &str) -> ConnectOptions<Self> {
+        let mut con_opts = sqlx::postgres::PgConnectOptions::new()
+            .username(&conf.sql.user)
+            .password(pass)
+            .database(&conf.sql.database)
+            .application_name(clap::crate_name!());
+        con_opts = match *host {
+            SqlHost::Socket(socket) => con_opts.socket(socket),
+            SqlHost::Tcp(name, None) => con_opts.host(name),
+            SqlHost::Tcp(name, Some(port)) => con_opts.host(name).port(port),
+        };
+        if let Some(mode) = conf.sql.sslmode {
+            use sqlx::postgres::PgSslMode;
+            con_opts = con_opts.ssl_mode(match mode {
+                ConfigSqlSslMode::Disable => PgSslMode::Disable,
+                ConfigSqlSslMode::Prefer => PgSslMode::Prefer,
+                ConfigSqlSslMode::Require => PgSslMode::Require,
+                ConfigSqlSslMode::VerifyCa => PgSslMode::VerifyCa,
+                ConfigSqlSslMode::VerifyFull => PgSslMode::VerifyFull,
+            });
+        }
+        if let Some(cert) = &conf.sql.sslrootcert {
+            con_opts = con_opts.ssl_root_cert(cert);
+        }
+        if let Some(cert) = &conf.sql.sslcert {
+            con_opts = con_opts.ssl_client_cert(cert);
+        }
+        if let Some(key) = &conf.sql.sslkey {
+            con_opts = con_opts.ssl_client_key(key);
+        }
+        con_opts
+    }
+
+    fn check_server<'c>(
+        conf: &'c Config,
+        host: SqlHost<'c>,
+        conn: &'c mut Self::Connection,
+    ) -> BoxFuture<'c, Result<(), String>> {
+        Box::pin(async move {
+            if conf.sql.target_session_attrs != ConfigSqlTargetSessionAttrs::Any {
+                let (standby, read_only): (bool, String) = sqlx::query_as(
+                    "SELECT pg_is_in_recovery(), current_setting('transaction_read_only')",
+                )
+                .fetch_one(&mut *conn)
+                .await
+                .map_err(|err| format!("Could not query the server state: {}", err))?;
+                check_session_attrs(conf, standby, read_only == "on")?;
+            }
+
+            // Unix sockets are never encrypted, like in libpq the sslmode does not apply
+            if conf.sql.sslmode >= Some(ConfigSqlSslMode::Require)
+                && !matches!(host, SqlHost::Socket(_))
+            {
+                let ssl: Option<bool> =
+                    sqlx::query_scalar("SELECT ssl FROM pg_stat_ssl WHERE pid = pg_backend_pid()")
+                        .fetch_optional(&mut *conn)
+                        .await
+                        .map_err(|err| format!("Could not query the TLS status: {}", err))?;
+                if !ssl.unwrap_or(false) {
+                    return Err(format!(
+                        "The connection is not encrypted, but sslmode = {}",
+                        conf.sql.sslmode.unwrap()
+                    ));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Driver for sqlx::MySql {
+    fn connect_options(conf: &Config, host: &SqlHost, pass: &str) -> ConnectOptions<Self> {
+        let mut con_opts = sqlx::mysql::MySqlConnectOptions::new()
+            .username(&conf.sql.user)
+            .password(pass)
+            .database(&conf.sql.database);
+        con_opts = match *host {
+            SqlHost::Socket(socket) => con_opts.socket(socket),
+            SqlHost::Tcp(name, None) => con_opts.host(name),
+            SqlHost::Tcp(name, Some(port)) => con_opts.host(name).port(port),
+        };
+        if let Some(mode) = conf.sql.sslmode {
+            use sqlx::mysql::MySqlSslMode;
+            con_opts = con_opts.ssl_mode(match mode {
+                ConfigSqlSslMode::Disable => MySqlSslMode::Disabled,
+                ConfigSqlSslMode::Prefer => MySqlSslMode::Preferred,
+                ConfigSqlSslMode::Require => MySqlSslMode::Required,
+                ConfigSqlSslMode::VerifyCa => MySqlSslMode::VerifyCa,
+                ConfigSqlSslMode::VerifyFull => MySqlSslMode::VerifyIdentity,
+            });
+        }
+        if let Some(cert) = &conf.sql.sslrootcert {
+            con_opts = con_opts.ssl_ca(cert);
+        }
+        if let Some(cert) = &conf.sql.sslcert {
+            con_opts = con_opts.ssl_client_cert(cert);
+        }
+        if let Some(key) = &conf.sql.sslkey {
+            con_opts = con_opts.ssl_client_key(key);
+        }
+        con_opts
+    }
+
+    fn check_server<'c>(
+        conf: &'c Config,
+        _host: SqlHost<'c>,
+        conn: &'c mut Self::Connection,
+    ) -> BoxFuture<'c, Result<(), String>> {
+        Box::pin(async move {
+            if conf.sql.target_session_attrs != ConfigSqlTargetSessionAttrs::Any {
+                // Replicas are usually configured read-only
+                let read_only: i64 =
+                    sqlx::query_scalar("SELECT CAST(@@global.read_only AS SIGNED)")
+                        .fetch_one(&mut *conn)
+                        .await
+                        .map_err(|err| format!("Could not query the server state: {}", err))?;
+                check_session_attrs(conf, read_only != 0, read_only != 0)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Driver for sqlx::Sqlite {
+    fn connect_options(conf: &Config, _host: &SqlHost, _pass: &str) -> ConnectOptions<Self> {
+        sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(&conf.sql.database)
+            .read_only(conf.sql.read_only)
+    }
+
+    fn check_server<'c>(
+        _conf: &'c Config,
+        _host: SqlHost<'c>,
+        _conn: &'c mut Self::Connection,
+    ) -> BoxFuture<'c, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn prepare(
+        approx: ConfigSqlApprox,
+        conn: &mut Self::Connection,
+    ) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+        Box::pin(async move {
+            // The expression used by the other databases nests too deep for SQLite
+            if approx == ConfigSqlApprox::Cologne {
+                let mut handle = conn.lock_handle().await?;
+                let rc = unsafe {
+                    libsqlite3_sys::sqlite3_create_function_v2(
+                        handle.as_raw_handle().as_ptr(),
+                        c"cologne".as_ptr(),
+                        1,
+                        libsqlite3_sys::SQLITE_UTF8 | libsqlite3_sys::SQLITE_DETERMINISTIC,
+                        std::ptr::null_mut(),
+                        Some(sqlite_cologne),
+                        None,
+                        None,
+                        None,
+                    )
+                };
+                if rc != libsqlite3_sys::SQLITE_OK {
+                    return Err(sqlx::Error::Configuration(
+                        format!("Can not create the cologne() function: error {}", rc).into(),
+                    ));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// `cologne(text)` with the Kölner Phonetik code, NULL stays NULL.
+unsafe extern "C" fn sqlite_cologne(
+    ctx: *mut libsqlite3_sys::sqlite3_context,
+    _argc: c_int,
+    argv: *mut *mut libsqlite3_sys::sqlite3_value,
+) {
+    let value = *argv;
+    if libsqlite3_sys::sqlite3_value_type(value) == libsqlite3_sys::SQLITE_NULL {
+        libsqlite3_sys::sqlite3_result_null(ctx);
+        return;
+    }
+    let text = libsqlite3_sys::sqlite3_value_text(value);
+    let text = if text.is_null() {
+        &[][..]
+    } else {
+        // The length has to be read after the conversion to text
+        std::slice::from_raw_parts(text, libsqlite3_sys::sqlite3_value_bytes(value) as usize)
+    };
+    let code = cologne::encode(&String::from_utf8_lossy(text));
+    libsqlite3_sys::sqlite3_result_text(
+        ctx,
+        code.as_ptr() as *const c_char,
+        code.len() as c_int,
+        libsqlite3_sys::SQLITE_TRANSIENT(),
+    );
+}
+
+fn check_session_attrs(conf: &Config, standby: bool, read_only: bool) -> Result<(), String> {
+    match conf.sql.target_session_attrs {
+        ConfigSqlTargetSessionAttrs::ReadWrite if read_only => {
+            Err("The server is read-only, but target_session_attrs = read-write".to_owned())
+        }
+        ConfigSqlTargetSessionAttrs::Standby if !standby => {
+            Err("The server is not a standby, but target_session_attrs = standby".to_owned())
+        }
+        _ => Ok(()),
+    }
+}
+
+fn pool_options<DB: Driver>(
+    conf: &Config,
+    failed_over: Arc<Mutex<Option<Instant>>>,
+) -> sqlx::pool::PoolOptions<DB> {
+    let conf_pool = &conf.sql.pool;
+    let seconds = |s: u64| (s > 0).then(|| Duration::from_secs(s));
+    let approx = conf.sql.approx;
+    let mut pool_opts = sqlx::pool::PoolOptions::<DB>::new()
+        .after_connect(move |conn, _| DB::prepare(approx, conn))
+        .before_acquire(move |_, meta| {
+            // Retire connections to the host used before the last failover
+            let stale = failed_over
+                .lock()
+                .unwrap()
+                .is_some_and(|at| meta.age > at.elapsed());
+            Box::pin(async move { Ok(!stale) })
+        });
+    if let Some(timeout) = conf_pool.acquire_timeout {
+        pool_opts = pool_opts.acquire_timeout(Duration::from_secs(timeout));
+    }
+    if conf.server.seccomp {
+        // Can't open a connection when seccomp filter is active
+        if conf_pool.min_connections.is_some()
+            || conf_pool.idle_timeout.is_some()
+            || conf_pool.max_lifetime.is_some()
+        {
+            log::warn!(
+                "sql.pool.min_connections, idle_timeout and max_lifetime are ignored with seccomp"
+            );
+        }
+        let t = conf_pool
+            .max_connections
+            .unwrap_or(conf.server.threads as u32);
+        pool_opts = pool_opts
+            .max_lifetime(None)
+            .idle_timeout(None)
+            .max_connections(t)
+            .min_connections(t);
+    } else {
+        if let Some(max) = conf_pool.max_connections {
+            pool_opts = pool_opts.max_connections(max);
+        }
+        if let Some(min) = conf_pool.min_connections {
+            pool_opts = pool_opts.min_connections(min);
+        }
+        if let Some(timeout) = conf_pool.idle_timeout {
+            pool_opts = pool_opts.idle_timeout(seconds(timeout));
+        }
+        if let Some(lifetime) = conf_pool.max_lifetime {
+            pool_opts = pool_opts.max_lifetime(seconds(lifetime));
+        }
+    }
+    pool_opts
+}
+
+/// The connection pool, once the database could be reached.
+pub struct SqlPool<DB: sqlx::Database> {
+    state: RwLock<PoolState<DB>>,
+    /// Wakes the monitor to check the database host before its next interval
+    check: Notify,
+}
+
+enum PoolState<DB: sqlx::Database> {
+    /// The first connection attempt is still running
+    Connecting,
+    /// Waiting to retry after a failed attempt
+    Failed,
+    Connected(sqlx::Pool<DB>),
+}
+
+impl<DB: sqlx::Database> Default for SqlPool<DB> {
+    fn default() -> Self {
+        Self {
+            state: RwLock::new(PoolState::Connecting),
+            check: Notify::new(),
+        }
+    }
+}
+
+impl<DB: sqlx::Database> SqlPool<DB> {
+    pub fn get(&self) -> Result<sqlx::Pool<DB>, BackendError> {
+        match &*self.state.read().unwrap() {
+            PoolState::Connecting => Err(BackendError::Connecting),
+            PoolState::Failed => Err(BackendError::Unavailable),
+            PoolState::Connected(pool) => Ok(pool.clone()),
+        }
+    }
+
+    /// Connection errors of queries make the monitor check the host right away,
+    /// so it fails over without waiting for the next interval.
+    pub fn failed(&self, err: &sqlx::Error) {
+        if matches!(
+            err,
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolTimedOut
+        ) {
+            self.check.notify_one();
+        }
+    }
+
+    fn set(&self, state: PoolState<DB>) {
+        *self.state.write().unwrap() = state;
+    }
+}
+
+/// Opens the connection pool to the first suitable host, retrying with backoff
+/// until it succeeds, and keeps it up to date.
+///
+/// The password is read again before each retry, in case the database rejected it.
+pub async fn connect<DB: Driver>(conf: Arc<Config>, mut pass: String, sql_pool: Arc<SqlPool<DB>>) {
+    let hosts = conf.sql.hosts();
+    let failed_over = Arc::new(Mutex::new(None));
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempts = 0;
+    let (pool, host) = loop {
+        if attempts > 0 {
+            match conf.sql.password() {
+                Ok(new_pass) => pass = new_pass,
+                Err(err) => log::warn!("{}", err),
+            }
+        }
+        let opened = match find_host::<DB>(&conf, &hosts, 0, &pass, true).await {
+            Ok(host) => pool_options(&conf, failed_over.clone())
+                .connect_with(DB::connect_options(&conf, &hosts[host], &pass))
+                .await
+                .map(|pool| (pool, host))
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err),
+        };
+        match opened {
+            Ok(opened) => break opened,
+            Err(err) => {
+                attempts += 1;
+                sql_pool.set(PoolState::Failed);
+                match conf.sql.sslmode {
+                    Some(mode) => log::error!(
+                        "Could not connect to database (sslmode = {}): {}, retrying in {}s",
+                        mode,
+                        err,
+                        backoff.as_secs()
+                    ),
+                    None => log::error!(
+                        "Could not connect to database: {}, retrying in {}s",
+                        err,
+                        backoff.as_secs()
+                    ),
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    };
+
+    let target = match conf.sql.backend {
+        ConfigSqlBackend::SQLite => conf.sql.database.to_owned(),
+        _ => format!("host {}", hosts[host]),
+    };
+    if attempts > 0 {
+        log::warn!(
+            "connected to database {} after {} failed attempts",
+            target,
+            attempts
+        );
+    } else {
+        log::info!("connected to database {}", target);
+    }
+    sql_pool.set(PoolState::Connected(pool.clone()));
+
+    // The filter forbids opening files and sockets, all connections are opened up front anyway
+    if !conf.server.seccomp && conf.sql.backend != ConfigSqlBackend::SQLite {
+        tokio::spawn(monitor(
+            conf.clone(),
+            sql_pool,
+            pool,
+            host,
+            pass,
+            failed_over,
+        ));
+    }
+}
+
+/// Returns the first host, starting at `start`, that accepts a connection and passes the checks.
+async fn find_host<DB: Driver>(
+    conf: &Config,
+    hosts: &[SqlHost<'_>],
+    start: usize,
+    pass: &str,
+    log_failures: bool,
+) -> Result<usize, String> {
+    let mut last_err = String::new();
+    for i in (0..hosts.len()).map(|i| (start + i) % hosts.len()) {
+        let host = hosts[i];
+        let con_opts = DB::connect_options(conf, &host, pass);
+        let checked = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let mut conn = DB::Connection::connect_with(&con_opts)
+                .await
+                .map_err(|err| err.to_string())?;
+            let checked = DB::check_server(conf, host, &mut conn).await;
+            let _ = conn.close().await;
+            checked
+        })
+        .await
+        .unwrap_or_else(|_| Err("Connection timed out".to_owned()));
+        match checked {
+            Ok(()) => return Ok(i),
+            Err(err) => {
+                if log_failures && hosts.len() > 1 {
+                    log::warn!("database host {}: {}", host, err);
+                }
+                last_err = err;
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Hands a changed password to new connections, logs when the database
+/// becomes unreachable and fails over to the next suitable host.
+///
+/// The host is checked every interval and whenever a query lost its connection.
+async fn monitor<DB: Driver>(
+    conf: Arc<Config>,
+    sql_pool: Arc<SqlPool<DB>>,
+    pool: sqlx::Pool<DB>,
+    mut host: usize,
+    mut pass: String,
+    failed_over: Arc<Mutex<Option<Instant>>>,
+) {
+    let hosts = conf.sql.hosts();
+    let watch_password = matches!(conf.sql.password_file(), Ok(Some(_)));
+
+    let mut failing = false;
+    let mut down = false;
+    while !pool.is_closed() {
+        tokio::select! {
+            _ = tokio::time::sleep(MONITOR_INTERVAL) => {}
+            // While the database is down, failing queries would keep waking it up
+            _ = sql_pool.check.notified(), if !down => {}
+        }
+
+        if watch_password {
+            match conf.sql.password() {
+                Ok(new_pass) => {
+                    failing = false;
+                    if new_pass != pass {
+                        pass = new_pass;
+                        pool.set_connect_options(DB::connect_options(&conf, &hosts[host], &pass));
+                        log::info!("database password changed, using it for new connections");
+                    }
+                }
+                Err(err) => {
+                    // Only log once until the file is readable again
+                    if !failing {
+                        log::warn!("{}", err);
+                        failing = true;
+                    }
+                }
+            }
+        }
+
+        let checked = tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let mut conn = pool.acquire().await.map_err(|err| err.to_string())?;
+            DB::check_server(&conf, hosts[host], &mut conn).await
+        })
+        .await
+        .unwrap_or_else(|_| Err("Connection timed out".to_owned()));
+        match checked {
+            Ok(()) if down => {
+                down = false;
+                log::warn!("database host {} is available again", hosts[host]);
+            }
+            Ok(()) => {}
+            Err(err) => {
+                // Only log the state changes while the database is down
+                if !down {
+                    log::error!("database host {} failed: {}", hosts[host], err);
+                }
+                match find_host::<DB>(&conf, &hosts, host + 1, &pass, !down).await {
+                    // A blip of the current host is not worth a second message
+                    Ok(next) if next == host => {
+                        if down {
+                            log::warn!("database host {} is available again", hosts[host]);
+                        }
+                    }
+                    Ok(next) => {
+                        host = next;
+                        *failed_over.lock().unwrap() = Some(Instant::now());
+                        pool.set_connect_options(DB::connect_options(&conf, &hosts[host], &pass));
+                        log::warn!("failed over to database host {}", hosts[host]);
+                    }
+                    Err(_) => {
+                        down = true;
+                        continue;
+                    }
+                }
+                down = false;
+            }
+        }
+    }
+}
@@ -0,0 +1,1056 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Dialect independent representation of the generated SQL queries.
+//!
+//! Column expressions are taken verbatim from the configuration,
+//! all values are passed to the database as bound parameters.
+
+use crate::cologne;
+use crate::config::ConfigSqlBackend;
+
+pub struct Select {
+    /// Columns and their aliases
+    pub columns: Vec<(Column, String)>,
+    pub table: String,
+    pub filter: Option<Condition>,
+    pub order_by: Vec<Order>,
+    pub limit: Option<u32>,
+    /// Only used together with `limit`, MySQL and SQLite do not support it alone
+    pub offset: Option<u32>,
+}
+
+pub enum Column {
+    /// Column expression from the configuration
+    Expr(String),
+    /// `'TRUE'` if the condition holds, `'FALSE'` otherwise
+    Matches(Condition),
+}
+
+pub enum Order {
+    /// Column expression in ascending order
+    Expr(String),
+    /// Case insensitive order of a column expression, empty values sort after all others
+    IgnoreCase { expr: String, reverse: bool },
+}
+
+/// A condition in three-valued logic like LDAP filters and SQL predicates,
+/// the empty `And` is true and the empty `Or` is false.
+pub enum Condition {
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+    /// Neither true nor false, like filters on unknown attributes (RFC 4511)
+    Undefined,
+    /// Case insensitive comparison of a column expression with a value
+    EqualsIgnoreCase(String, String),
+    /// Case insensitive match of a column expression with a pattern
+    Like(String, Vec<Pattern>),
+    /// The column expression sorts before the value in the order of `Order::IgnoreCase`
+    SortsBefore {
+        expr: String,
+        value: String,
+        reverse: bool,
+    },
+    /// The column expression is greater than or equal to the value
+    GreaterOrEqual(String, Typed),
+    /// The column expression is less than or equal to the value
+    LessOrEqual(String, Typed),
+    /// The column expression approximately matches the value
+    Approx(String, Approx),
+    /// The column expression matches the normalized value of an extensible filter
+    Rule(String, MatchingRule, String),
+    /// The column expression is not empty
+    Present(String),
+    /// A predicate taken verbatim from the configuration
+    Sql(String),
+}
+
+impl Condition {
+    pub fn is_true(&self) -> bool {
+        matches!(self, Condition::And(conditions) if conditions.is_empty())
+    }
+
+    pub fn is_false(&self) -> bool {
+        matches!(self, Condition::Or(conditions) if conditions.is_empty())
+    }
+
+    /// Conjunction with the constant operands folded.
+    pub fn and(conditions: Vec<Condition>) -> Condition {
+        let mut undefined = false;
+        let mut rest = Vec::with_capacity(conditions.len());
+        for condition in conditions {
+            match condition {
+                c if c.is_false() => return c,
+                Condition::Undefined => undefined = true,
+                Condition::And(inner) => rest.extend(inner),
+                c => rest.push(c),
+            }
+        }
+        Self::fold(rest, undefined, Condition::And)
+    }
+
+    /// Disjunction with the constant operands folded.
+    pub fn or(conditions: Vec<Condition>) -> Condition {
+        let mut undefined = false;
+        let mut rest = Vec::with_capacity(conditions.len());
+        for condition in conditions {
+            match condition {
+                c if c.is_true() => return c,
+                Condition::Undefined => undefined = true,
+                Condition::Or(inner) => rest.extend(inner),
+                c => rest.push(c),
+            }
+        }
+        Self::fold(rest, undefined, Condition::Or)
+    }
+
+    fn fold(
+        mut rest: Vec<Condition>,
+        undefined: bool,
+        group: fn(Vec<Condition>) -> Condition,
+    ) -> Condition {
+        match (rest.len(), undefined) {
+            (0, true) => Condition::Undefined,
+            (1, false) => rest.pop().unwrap(),
+            (_, true) => {
+                // Still decides the result if the other operands do not
+                rest.push(Condition::Undefined);
+                group(rest)
+            }
+            _ => group(rest),
+        }
+    }
+
+    /// Negation with constants folded.
+    pub fn not(condition: Condition) -> Condition {
+        match condition {
+            c if c.is_true() => Condition::Or(Vec::new()),
+            c if c.is_false() => Condition::And(Vec::new()),
+            Condition::Undefined => Condition::Undefined,
+            Condition::Not(inner) => *inner,
+            c => Condition::Not(Box::new(c)),
+        }
+    }
+
+    /// Simplifies a condition whose rows only match if it is true,
+    /// Undefined is the same as false then unless it is negated.
+    pub fn matching(self) -> Condition {
+        match self {
+            Condition::Undefined => Condition::Or(Vec::new()),
+            Condition::And(conditions) => {
+                if conditions.iter().any(|c| matches!(c, Condition::Undefined)) {
+                    return Condition::Or(Vec::new());
+                }
+                Condition::and(conditions.into_iter().map(Condition::matching).collect())
+            }
+            Condition::Or(conditions) => {
+                Condition::or(conditions.into_iter().map(Condition::matching).collect())
+            }
+            c => c,
+        }
+    }
+}
+
+/// A value compared according to the type of the attribute.
+pub enum Typed {
+    /// Compared case insensitively
+    Text(String),
+    /// A decimal number
+    Number(String),
+    /// `YYYY-MM-DD HH:MM:SS.ffffff` in UTC
+    Timestamp(String),
+}
+
+/// Algorithms of approximate matches.
+pub enum Approx {
+    /// Trigram similarity of pg_trgm above `pg_trgm.similarity_threshold`
+    Trigram(String),
+    /// Same `soundex()` code
+    Soundex(String),
+    /// Same `dmetaphone()` code of fuzzystrmatch
+    Dmetaphone(String),
+    /// The Kölner Phonetik code of the value
+    Cologne(String),
+}
+
+/// Matching rules of extensible filters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MatchingRule {
+    CaseIgnore,
+    CaseExact,
+    /// Ignores spaces
+    NumericString,
+    /// Ignores case, spaces and hyphens
+    TelephoneNumber,
+    Integer,
+}
+
+pub enum Pattern {
+    Literal(String),
+    /// Matches any string, including the empty one
+    Any,
+}
+
+/// The SQL flavour of a database.
+pub trait Dialect: Send + Sync {
+    fn quote_identifier(&self, ident: &str) -> String {
+        format!("\"{}\"", ident.replace('"', "\"\""))
+    }
+
+    /// Bind parameter for the nth (starting at 1) value
+    fn placeholder(&self, _n: usize) -> String {
+        "?".to_owned()
+    }
+
+    fn equals_ignore_case(&self, lhs: &str, rhs: &str) -> String {
+        format!("LOWER({}) = LOWER({})", lhs, rhs)
+    }
+
+    fn compare_ignore_case(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        format!("LOWER({}) {} LOWER({})", lhs, op, rhs)
+    }
+
+    /// Case sensitive even if the collation of the column is not
+    fn equals_case_exact(&self, lhs: &str, rhs: &str) -> String {
+        format!("{} = {}", lhs, rhs)
+    }
+
+    fn order_ignore_case(&self, expr: &str) -> String {
+        format!("LOWER({})", expr)
+    }
+
+    fn concat(&self, lhs: &str, rhs: &str) -> String {
+        format!("({} || {})", lhs, rhs)
+    }
+
+    /// The Kölner Phonetik code of the column expression
+    fn cologne(&self, expr: &str) -> String {
+        cologne::sql_expression(self, expr)
+    }
+
+    /// Compares the text column expression as a number, empty values do not match
+    fn compare_number(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        format!(
+            "CAST(NULLIF({}, '') AS NUMERIC) {} CAST({} AS NUMERIC)",
+            lhs, op, rhs
+        )
+    }
+
+    /// Compares the text column expression as a timestamp with a UTC timestamp,
+    /// empty values do not match
+    fn compare_timestamp(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        format!(
+            "CAST(NULLIF({}, '') AS TIMESTAMP) {} CAST({} AS TIMESTAMP)",
+            lhs, op, rhs
+        )
+    }
+
+    /// Character to escape the LIKE wildcards with
+    fn like_escape(&self) -> char {
+        '\\'
+    }
+
+    fn like_ignore_case(&self, lhs: &str, pattern: &str) -> String {
+        format!(
+            "LOWER({}) LIKE LOWER({}) ESCAPE '{}'",
+            lhs,
+            pattern,
+            self.like_escape()
+        )
+    }
+}
+
+pub struct PostgreSQL;
+
+impl Dialect for PostgreSQL {
+    fn placeholder(&self, n: usize) -> String {
+        format!("${}", n)
+    }
+
+    fn like_ignore_case(&self, lhs: &str, pattern: &str) -> String {
+        // Backslash is the default escape character and the literal '\'
+        // would depend on standard_conforming_strings
+        format!("LOWER({}) LIKE LOWER({})", lhs, pattern)
+    }
+
+    fn compare_timestamp(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        // Values without a time zone are in the one of the session
+        format!(
+            "CAST(NULLIF({}, '') AS TIMESTAMPTZ) AT TIME ZONE 'UTC' {} CAST({} AS TIMESTAMP)",
+            lhs, op, rhs
+        )
+    }
+}
+
+pub struct MySQL;
+
+impl Dialect for MySQL {
+    fn quote_identifier(&self, ident: &str) -> String {
+        format!("`{}`", ident.replace('`', "``"))
+    }
+
+    fn like_escape(&self) -> char {
+        // Works independently of the NO_BACKSLASH_ESCAPES sql mode
+        '!'
+    }
+
+    fn equals_case_exact(&self, lhs: &str, rhs: &str) -> String {
+        // The default collations are case insensitive
+        format!("CAST({} AS BINARY) = CAST({} AS BINARY)", lhs, rhs)
+    }
+
+    fn concat(&self, lhs: &str, rhs: &str) -> String {
+        // || is OR unless the PIPES_AS_CONCAT sql mode is set
+        format!("CONCAT({}, {})", lhs, rhs)
+    }
+
+    fn compare_number(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        // DECIMAL alone has no fractional digits
+        format!(
+            "CAST(NULLIF({}, '') AS DECIMAL(65, 30)) {} CAST({} AS DECIMAL(65, 30))",
+            lhs, op, rhs
+        )
+    }
+
+    fn compare_timestamp(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        // DATETIME has no time zone, the values are assumed to be in UTC
+        format!(
+            "CAST(NULLIF({}, '') AS DATETIME(6)) {} CAST({} AS DATETIME(6))",
+            lhs, op, rhs
+        )
+    }
+}
+
+pub struct SQLite;
+
+impl Dialect for SQLite {
+    fn equals_ignore_case(&self, lhs: &str, rhs: &str) -> String {
+        // LOWER() and NOCASE both only fold ASCII characters
+        format!("{} = {} COLLATE NOCASE", lhs, rhs)
+    }
+
+    fn compare_ignore_case(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        format!("{} {} {} COLLATE NOCASE", lhs, op, rhs)
+    }
+
+    fn equals_case_exact(&self, lhs: &str, rhs: &str) -> String {
+        format!("{} = {} COLLATE BINARY", lhs, rhs)
+    }
+
+    fn order_ignore_case(&self, expr: &str) -> String {
+        format!("{} COLLATE NOCASE", expr)
+    }
+
+    fn cologne(&self, expr: &str) -> String {
+        // Created on each connection by the pool
+        format!("cologne({})", expr)
+    }
+
+    fn compare_timestamp(&self, lhs: &str, op: &str, rhs: &str) -> String {
+        // There is no timestamp type, julianday() converts time zones to UTC
+        format!("julianday(NULLIF({}, '')) {} julianday({})", lhs, op, rhs)
+    }
+
+    fn like_ignore_case(&self, lhs: &str, pattern: &str) -> String {
+        // LIKE is case insensitive for ASCII characters
+        format!("{} LIKE {} ESCAPE '{}'", lhs, pattern, self.like_escape())
+    }
+}
+
+pub fn dialect(backend: ConfigSqlBackend) -> &'static dyn Dialect {
+    match backend {
+        ConfigSqlBackend::PostgreSQL => &PostgreSQL,
+        ConfigSqlBackend::MySQL => &MySQL,
+        ConfigSqlBackend::SQLite => &SQLite,
+    }
+}
+
+impl Select {
+    /// Renders the query and returns it together with the values to bind.
+    pub fn render(&self, dialect: &dyn Dialect) -> (String, Vec<String>) {
+        let mut w = Writer {
+            dialect,
+            sql: "SELECT ".to_owned(),
+            bindings: Vec::new(),
+        };
+
+        for (i, (column, alias)) in self.columns.iter().enumerate() {
+            if i > 0 {
+                w.sql.push_str(", ");
+            }
+            match column {
+                Column::Expr(col) => w.sql.push_str(col),
+                Column::Matches(condition) => {
+                    w.sql.push_str("CASE WHEN ");
+                    w.condition(condition);
+                    w.sql.push_str(" THEN 'TRUE' ELSE 'FALSE' END");
+                }
+            }
+            w.sql.push_str(" AS ");
+            w.sql.push_str(&dialect.quote_identifier(alias));
+        }
+        w.sql.push_str(" FROM ");
+        w.sql.push_str(&self.table);
+
+        if let Some(filter) = &self.filter {
+            w.sql.push_str(" WHERE ");
+            w.condition(filter);
+        }
+
+        for (i, order) in self.order_by.iter().enumerate() {
+            w.sql.push_str(if i == 0 { " ORDER BY " } else { ", " });
+            match order {
+                Order::Expr(expr) => w.sql.push_str(expr),
+                Order::IgnoreCase { expr, reverse } => {
+                    // NULLS LAST is not supported by MySQL
+                    let dir = if *reverse { " DESC" } else { "" };
+                    w.sql.push_str(&format!(
+                        "CASE WHEN {0} IS NULL OR {0} = '' THEN 1 ELSE 0 END{1}, {2}{1}",
+                        expr,
+                        dir,
+                        dialect.order_ignore_case(expr)
+                    ));
+                }
+            }
+        }
+
+        if let Some(limit) = self.limit {
+            w.sql.push_str(&format!(" LIMIT {}", limit));
+            if let Some(offset) = self.offset {
+                w.sql.push_str(&format!(" OFFSET {}", offset));
+            }
+        }
+
+        (w.sql, w.bindings)
+    }
+}
+
+struct Writer<'a> {
+    dialect: &'a dyn Dialect,
+    sql: String,
+    bindings: Vec<String>,
+}
+
+impl Writer<'_> {
+    fn bind(&mut self, value: String) -> String {
+        self.bindings.push(value);
+        self.dialect.placeholder(self.bindings.len())
+    }
+
+    fn condition(&mut self, condition: &Condition) {
+        match condition {
+            Condition::And(conditions) => self.group(conditions, " AND "),
+            Condition::Or(conditions) => self.group(conditions, " OR "),
+            Condition::Not(condition) => {
+                self.sql.push_str("(NOT ");
+                self.condition(condition);
+                self.sql.push(')');
+            }
+            Condition::Undefined => self.sql.push_str("NULL"),
+            Condition::EqualsIgnoreCase(col, value) => {
+                let p = self.bind(value.to_owned());
+                self.sql.push_str(&self.dialect.equals_ignore_case(col, &p));
+            }
+            Condition::Like(col, pattern) => {
+                let escape = self.dialect.like_escape();
+                let mut like = String::new();
+                for part in pattern {
+                    match part {
+                        Pattern::Literal(s) => {
+                            for c in s.chars() {
+                                if c == escape || c == '%' || c == '_' {
+                                    like.push(escape);
+                                }
+                                like.push(c);
+                            }
+                        }
+                        Pattern::Any => like.push('%'),
+                    }
+                }
+                let p = self.bind(like);
+                self.sql.push_str(&self.dialect.like_ignore_case(col, &p));
+            }
+            Condition::SortsBefore {
+                expr,
+                value,
+                reverse,
+            } => {
+                let p = self.bind(value.to_owned());
+                // Empty values sort after all others
+                self.sql.push_str(&if *reverse {
+                    format!(
+                        "({0} IS NULL OR {0} = '' OR {1})",
+                        expr,
+                        self.dialect.compare_ignore_case(expr, ">", &p)
+                    )
+                } else {
+                    format!(
+                        "({} <> '' AND {})",
+                        expr,
+                        self.dialect.compare_ignore_case(expr, "<", &p)
+                    )
+                });
+            }
+            Condition::GreaterOrEqual(col, value) => self.compare(col, ">=", value),
+            Condition::LessOrEqual(col, value) => self.compare(col, "<=", value),
+            Condition::Approx(col, approx) => {
+                let sql = match approx {
+                    Approx::Trigram(value) => {
+                        let p = self.bind(value.to_owned());
+                        format!("{} % {}", col, p)
+                    }
+                    Approx::Soundex(value) => {
+                        let p = self.bind(value.to_owned());
+                        // The code of empty values is empty as well
+                        format!("({} <> '' AND SOUNDEX({}) = SOUNDEX({}))", col, col, p)
+                    }
+                    Approx::Dmetaphone(value) => {
+                        let p = self.bind(value.to_owned());
+                        format!(
+                            "({} <> '' AND dmetaphone({}) = dmetaphone({}))",
+                            col, col, p
+                        )
+                    }
+                    Approx::Cologne(code) => {
+                        let p = self.bind(code.to_owned());
+                        format!("{} = {}", self.dialect.cologne(col), p)
+                    }
+                };
+                self.sql.push_str(&sql);
+            }
+            Condition::Rule(col, rule, value) => {
+                let p = self.bind(value.to_owned());
+                let sql = match rule {
+                    MatchingRule::CaseIgnore => self.dialect.equals_ignore_case(col, &p),
+                    MatchingRule::CaseExact => self.dialect.equals_case_exact(col, &p),
+                    MatchingRule::NumericString => format!("REPLACE({}, ' ', '') = {}", col, p),
+                    MatchingRule::TelephoneNumber => self.dialect.equals_ignore_case(
+                        &format!("REPLACE(REPLACE({}, ' ', ''), '-', '')", col),
+                        &p,
+                    ),
+                    MatchingRule::Integer => self.dialect.compare_number(col, "=", &p),
+                };
+                self.sql.push_str(&sql);
+            }
+            Condition::Present(col) => {
+                self.sql.push_str(&format!("{} <> ''", col));
+            }
+            Condition::Sql(predicate) => {
+                self.sql.push_str(&format!("({})", predicate));
+            }
+        }
+    }
+
+    fn compare(&mut self, col: &str, op: &str, value: &Typed) {
+        let sql = match value {
+            Typed::Text(value) => {
+                let p = self.bind(value.to_owned());
+                // Empty values are absent, not less than everything
+                format!(
+                    "({} <> '' AND {})",
+                    col,
+                    self.dialect.compare_ignore_case(col, op, &p)
+                )
+            }
+            Typed::Number(value) => {
+                let p = self.bind(value.to_owned());
+                self.dialect.compare_number(col, op, &p)
+            }
+            Typed::Timestamp(value) => {
+                let p = self.bind(value.to_owned());
+                self.dialect.compare_timestamp(col, op, &p)
+            }
+        };
+        self.sql.push_str(&sql);
+    }
+
+    fn group(&mut self, conditions: &[Condition], sep: &str) {
+        if conditions.is_empty() {
+            // The empty AND is true, the empty OR is false (RFC 4526)
+            self.sql
+                .push_str(if sep == " AND " { "1 = 1" } else { "1 = 0" });
+            return;
+        }
+        self.sql.push('(');
+        for (i, condition) in conditions.iter().enumerate() {
+            if i > 0 {
+                self.sql.push_str(sep);
+            }
+            self.condition(condition);
+        }
+        self.sql.push(')');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ldap3_proto::parse_ldap_filter_str;
+
+    use super::*;
+    use crate::acl::{Access, Identity};
+    use crate::config::{Config, ConfigSqlApprox};
+    use crate::sql_backend::compile_filter;
+
+    const CONFIG: &str = r#"
+        [server]
+        [sql]
+        backend = "PostgreSQL"
+        database = "directory"
+        table = "users"
+        [ldap]
+        suffix = "ou=People,dc=example,dc=com"
+        [mappings]
+        cn = "uid"
+        mail = "email"
+        telephoneNumber = "phone"
+        employeeNumber = { column = "CAST(num AS TEXT)", type = "number" }
+        createTimestamp = { column = "CAST(created AS TEXT)", type = "timestamp" }
+    "#;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!("{}{}", CONFIG, extra)).unwrap()
+    }
+
+    fn render(condition: &Condition, dialect: &dyn Dialect) -> (String, Vec<String>) {
+        let mut w = Writer {
+            dialect,
+            sql: String::new(),
+            bindings: Vec::new(),
+        };
+        w.condition(condition);
+        (w.sql, w.bindings)
+    }
+
+    fn sql(condition: &Condition) -> String {
+        render(condition, &PostgreSQL).0
+    }
+
+    fn eq(value: &str) -> Condition {
+        Condition::EqualsIgnoreCase("uid".to_owned(), value.to_owned())
+    }
+
+    /// Compiles the filter for an identity with the rights of the config and renders
+    /// the condition rows have to match.
+    fn compile(conf: &Config, dialect: &dyn Dialect, filter: &str) -> (String, Vec<String>) {
+        let access = Access::new(conf, Identity::Anonymous);
+        let filter = parse_ldap_filter_str(filter).unwrap();
+        render(&compile_filter(conf, &access, &filter).matching(), dialect)
+    }
+
+    fn select() -> Select {
+        Select {
+            columns: vec![
+                (Column::Expr("uid".to_owned()), "cn".to_owned()),
+                (
+                    Column::Matches(Condition::EqualsIgnoreCase(
+                        "email".to_owned(),
+                        "Jane@example.com".to_owned(),
+                    )),
+                    "a\"b`c".to_owned(),
+                ),
+            ],
+            table: "users".to_owned(),
+            filter: Some(Condition::And(vec![
+                Condition::Like(
+                    "name".to_owned(),
+                    vec![Pattern::Literal("50%_a\\b!".to_owned()), Pattern::Any],
+                ),
+                Condition::Or(vec![eq("42"), Condition::Undefined]),
+            ])),
+            order_by: vec![
+                Order::IgnoreCase {
+                    expr: "sn".to_owned(),
+                    reverse: true,
+                },
+                Order::Expr("uid".to_owned()),
+            ],
+            limit: Some(10),
+            offset: Some(20),
+        }
+    }
+
+    #[test]
+    fn render_postgresql() {
+        let (sql, bindings) = select().render(&PostgreSQL);
+        assert_eq!(
+            sql,
+            "SELECT uid AS \"cn\", \
+             CASE WHEN LOWER(email) = LOWER($1) THEN 'TRUE' ELSE 'FALSE' END AS \"a\"\"b`c\" \
+             FROM users \
+             WHERE (LOWER(name) LIKE LOWER($2) AND (LOWER(uid) = LOWER($3) OR NULL)) \
+             ORDER BY CASE WHEN sn IS NULL OR sn = '' THEN 1 ELSE 0 END DESC, LOWER(sn) DESC, uid \
+             LIMIT 10 OFFSET 20"
+        );
+        assert_eq!(bindings, ["Jane@example.com", "50\\%\\_a\\\\b!%", "42"]);
+    }
+
+    #[test]
+    fn render_mysql() {
+        let (sql, bindings) = select().render(&MySQL);
+        assert_eq!(
+            sql,
+            "SELECT uid AS `cn`, \
+             CASE WHEN LOWER(email) = LOWER(?) THEN 'TRUE' ELSE 'FALSE' END AS `a\"b``c` \
+             FROM users \
+             WHERE (LOWER(name) LIKE LOWER(?) ESCAPE '!' AND (LOWER(uid) = LOWER(?) OR NULL)) \
+             ORDER BY CASE WHEN sn IS NULL OR sn = '' THEN 1 ELSE 0 END DESC, LOWER(sn) DESC, uid \
+             LIMIT 10 OFFSET 20"
+        );
+        assert_eq!(bindings, ["Jane@example.com", "50!%!_a\\b!!%", "42"]);
+    }
+
+    #[test]
+    fn render_sqlite() {
+        let (sql, bindings) = select().render(&SQLite);
+        assert_eq!(
+            sql,
+            "SELECT uid AS \"cn\", \
+             CASE WHEN email = ? COLLATE NOCASE THEN 'TRUE' ELSE 'FALSE' END AS \"a\"\"b`c\" \
+             FROM users \
+             WHERE (name LIKE ? ESCAPE '\\' AND (uid = ? COLLATE NOCASE OR NULL)) \
+             ORDER BY CASE WHEN sn IS NULL OR sn = '' THEN 1 ELSE 0 END DESC, sn COLLATE NOCASE DESC, uid \
+             LIMIT 10 OFFSET 20"
+        );
+        assert_eq!(bindings, ["Jane@example.com", "50\\%\\_a\\\\b!%", "42"]);
+    }
+
+    #[test]
+    fn render_case_exact() {
+        let condition = Condition::Rule("uid".to_owned(), MatchingRule::CaseExact, "A".to_owned());
+        assert_eq!(render(&condition, &PostgreSQL).0, "uid = $1");
+        assert_eq!(
+            render(&condition, &MySQL).0,
+            "CAST(uid AS BINARY) = CAST(? AS BINARY)"
+        );
+        assert_eq!(render(&condition, &SQLite).0, "uid = ? COLLATE BINARY");
+    }
+
+    #[test]
+    fn render_constants() {
+        assert_eq!(sql(&Condition::And(Vec::new())), "1 = 1");
+        assert_eq!(sql(&Condition::Or(Vec::new())), "1 = 0");
+        assert_eq!(sql(&Condition::Undefined), "NULL");
+        assert_eq!(
+            sql(&Condition::Not(Box::new(Condition::Undefined))),
+            "(NOT NULL)"
+        );
+    }
+
+    #[test]
+    fn and_folds_constants() {
+        assert!(Condition::and(vec![eq("1"), Condition::Or(Vec::new())]).is_false());
+        assert_eq!(
+            sql(&Condition::and(vec![
+                Condition::And(Vec::new()),
+                Condition::Undefined
+            ])),
+            "NULL"
+        );
+        assert_eq!(
+            sql(&Condition::and(vec![Condition::And(Vec::new()), eq("1")])),
+            "LOWER(uid) = LOWER($1)"
+        );
+        assert_eq!(
+            sql(&Condition::and(vec![
+                Condition::Undefined,
+                Condition::And(vec![eq("1"), eq("2")]),
+            ])),
+            "(LOWER(uid) = LOWER($1) AND LOWER(uid) = LOWER($2) AND NULL)"
+        );
+        assert!(Condition::and(Vec::new()).is_true());
+    }
+
+    #[test]
+    fn or_folds_constants() {
+        assert!(Condition::or(vec![eq("1"), Condition::And(Vec::new())]).is_true());
+        assert_eq!(
+            sql(&Condition::or(vec![
+                Condition::Or(Vec::new()),
+                Condition::Undefined
+            ])),
+            "NULL"
+        );
+        assert_eq!(
+            sql(&Condition::or(vec![Condition::Or(Vec::new()), eq("1")])),
+            "LOWER(uid) = LOWER($1)"
+        );
+        assert_eq!(
+            sql(&Condition::or(vec![
+                Condition::Or(vec![eq("1"), eq("2")]),
+                Condition::Undefined,
+            ])),
+            "(LOWER(uid) = LOWER($1) OR LOWER(uid) = LOWER($2) OR NULL)"
+        );
+        assert!(Condition::or(Vec::new()).is_false());
+    }
+
+    #[test]
+    fn not_folds_constants() {
+        assert!(Condition::not(Condition::And(Vec::new())).is_false());
+        assert!(Condition::not(Condition::Or(Vec::new())).is_true());
+        assert_eq!(sql(&Condition::not(Condition::Undefined)), "NULL");
+        assert_eq!(
+            sql(&Condition::not(Condition::not(eq("1")))),
+            "LOWER(uid) = LOWER($1)"
+        );
+        assert_eq!(
+            sql(&Condition::not(eq("1"))),
+            "(NOT LOWER(uid) = LOWER($1))"
+        );
+    }
+
+    #[test]
+    fn matching_treats_undefined_as_false() {
+        assert!(Condition::Undefined.matching().is_false());
+        assert!(Condition::And(vec![eq("1"), Condition::Undefined])
+            .matching()
+            .is_false());
+        assert_eq!(
+            sql(&Condition::Or(vec![eq("1"), Condition::Undefined]).matching()),
+            "LOWER(uid) = LOWER($1)"
+        );
+        assert!(Condition::Or(vec![Condition::Undefined])
+            .matching()
+            .is_false());
+        // NOT of Undefined is still Undefined, which SQL handles the same way
+        assert_eq!(
+            sql(
+                &Condition::Not(Box::new(Condition::Or(vec![eq("1"), Condition::Undefined])))
+                    .matching()
+            ),
+            "(NOT (LOWER(uid) = LOWER($1) OR NULL))"
+        );
+    }
+
+    #[test]
+    fn compile_equality_and_substring() {
+        let conf = config("");
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail=Jane@example.com)"),
+            (
+                "LOWER(email) = LOWER($1)".to_owned(),
+                vec!["Jane@example.com".to_owned()]
+            )
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail=j*n_e*@*)"),
+            (
+                "LOWER(email) LIKE LOWER($1)".to_owned(),
+                vec!["j%n\\_e%@%".to_owned()]
+            )
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(&(cn=42)(mail=*))").0,
+            "(LOWER(uid) = LOWER($1) AND email <> '')"
+        );
+    }
+
+    #[test]
+    fn compile_unmapped_attributes() {
+        let conf = config("");
+        assert_eq!(compile(&conf, &PostgreSQL, "(foo=bar)").0, "1 = 0");
+        assert_eq!(compile(&conf, &PostgreSQL, "(!(foo=bar))").0, "1 = 0");
+        assert_eq!(compile(&conf, &PostgreSQL, "(foo=*)").0, "1 = 0");
+        assert_eq!(compile(&conf, &PostgreSQL, "(!(foo=*))").0, "1 = 1");
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(|(foo=bar)(cn=42))").0,
+            "LOWER(uid) = LOWER($1)"
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(!(|(foo=bar)(cn=42)))").0,
+            "(NOT (LOWER(uid) = LOWER($1) OR NULL))"
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(!(&(foo=bar)(cn=42)))").0,
+            "(NOT (LOWER(uid) = LOWER($1) AND NULL))"
+        );
+    }
+
+    #[test]
+    fn compile_without_search_access() {
+        let conf = config(
+            r#"
+            [[acl]]
+            who = ["*"]
+            read = ["*"]
+            search = ["cn"]
+            "#,
+        );
+        // Like an unmapped attribute
+        assert_eq!(compile(&conf, &PostgreSQL, "(mail=*)").0, "1 = 0");
+        assert_eq!(compile(&conf, &PostgreSQL, "(!(mail=x))").0, "1 = 0");
+    }
+
+    #[test]
+    fn compile_ordering() {
+        let conf = config("");
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail>=m)").0,
+            "(email <> '' AND LOWER(email) >= LOWER($1))"
+        );
+        assert_eq!(
+            compile(&conf, &SQLite, "(mail<=m)").0,
+            "(email <> '' AND email <= ? COLLATE NOCASE)"
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(employeeNumber>=+1.5)"),
+            (
+                "CAST(NULLIF(CAST(num AS TEXT), '') AS NUMERIC) >= CAST($1 AS NUMERIC)".to_owned(),
+                vec!["1.5".to_owned()]
+            )
+        );
+        assert_eq!(
+            compile(&conf, &MySQL, "(employeeNumber<=-2)"),
+            (
+                "CAST(NULLIF(CAST(num AS TEXT), '') AS DECIMAL(65, 30)) <= CAST(? AS DECIMAL(65, 30))"
+                    .to_owned(),
+                vec!["-2".to_owned()]
+            )
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(createTimestamp<=20240101120000+0130)"),
+            (
+                "CAST(NULLIF(CAST(created AS TEXT), '') AS TIMESTAMPTZ) AT TIME ZONE 'UTC' <= CAST($1 AS TIMESTAMP)"
+                    .to_owned(),
+                vec!["2024-01-01 10:30:00.000000".to_owned()]
+            )
+        );
+        assert_eq!(
+            compile(&conf, &SQLite, "(createTimestamp>=2024010112Z)").0,
+            "julianday(NULLIF(CAST(created AS TEXT), '')) >= julianday(?)"
+        );
+        // Invalid assertion values are Undefined
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(employeeNumber>=abc)").0,
+            "1 = 0"
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(!(createTimestamp<=2024))").0,
+            "1 = 0"
+        );
+    }
+
+    #[test]
+    fn compile_approx() {
+        let mut conf = config("");
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail~=jane)").0,
+            "LOWER(email) = LOWER($1)"
+        );
+        conf.sql.approx = ConfigSqlApprox::Trigram;
+        assert_eq!(compile(&conf, &PostgreSQL, "(mail~=jane)").0, "email % $1");
+        conf.sql.approx = ConfigSqlApprox::Soundex;
+        assert_eq!(
+            compile(&conf, &MySQL, "(mail~=jane)").0,
+            "(email <> '' AND SOUNDEX(email) = SOUNDEX(?))"
+        );
+        conf.sql.approx = ConfigSqlApprox::Dmetaphone;
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail~=jane)").0,
+            "(email <> '' AND dmetaphone(email) = dmetaphone($1))"
+        );
+        conf.sql.approx = ConfigSqlApprox::Cologne;
+        assert_eq!(
+            compile(&conf, &SQLite, "(mail~=Meyer)"),
+            ("cologne(email) = ?".to_owned(), vec!["67".to_owned()])
+        );
+        // Would match the values without letters
+        assert_eq!(compile(&conf, &SQLite, "(mail~=42)").0, "1 = 0");
+    }
+
+    #[test]
+    fn compile_extensible() {
+        let conf = config("");
+        assert_eq!(
+            compile(&conf, &SQLite, "(mail:caseExactMatch:=Jane)").0,
+            "email = ? COLLATE BINARY"
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail:=Jane)").0,
+            "LOWER(email) = LOWER($1)"
+        );
+        assert_eq!(
+            compile(
+                &conf,
+                &PostgreSQL,
+                "(telephoneNumber:2.5.13.20:=\"+49 421-1\")"
+            ),
+            (
+                "LOWER(REPLACE(REPLACE(phone, ' ', ''), '-', '')) = LOWER($1)".to_owned(),
+                vec!["+494211".to_owned()]
+            )
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(employeeNumber:integerMatch:=007)"),
+            (
+                "CAST(NULLIF(CAST(num AS TEXT), '') AS NUMERIC) = CAST($1 AS NUMERIC)".to_owned(),
+                vec!["7".to_owned()]
+            )
+        );
+        // The rule does not apply to the attribute, is unknown or the value is invalid
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(mail:integerMatch:=7)").0,
+            "1 = 0"
+        );
+        assert_eq!(compile(&conf, &PostgreSQL, "(mail:1.2.3:=7)").0, "1 = 0");
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(!(mail:numericStringMatch:=abc))").0,
+            "1 = 0"
+        );
+        assert_eq!(compile(&conf, &PostgreSQL, "(foo:=bar)").0, "1 = 0");
+    }
+
+    #[test]
+    fn compile_extensible_without_type() {
+        let conf = config(
+            r#"
+            [[acl]]
+            who = ["*"]
+            read = ["*"]
+            search = ["cn", "mail"]
+            "#,
+        );
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(:numericStringMatch:=\"12 3\")"),
+            (
+                "(REPLACE(uid, ' ', '') = $1 OR REPLACE(email, ' ', '') = $2)".to_owned(),
+                vec!["123".to_owned(), "123".to_owned()]
+            )
+        );
+        assert_eq!(compile(&conf, &PostgreSQL, "(:integerMatch:=1)").0, "1 = 0");
+    }
+
+    #[test]
+    fn compile_extensible_dn_attributes() {
+        let conf = config(
+            r#"
+            [[acl]]
+            who = ["*"]
+            read = ["*"]
+            search = ["mail"]
+            "#,
+        );
+        // The suffix is part of the DN of all entries
+        assert_eq!(compile(&conf, &PostgreSQL, "(ou:dn:=people)").0, "1 = 1");
+        assert_eq!(compile(&conf, &PostgreSQL, "(ou:dn:=other)").0, "1 = 0");
+        assert_eq!(compile(&conf, &PostgreSQL, "(!(ou:dn:=other))").0, "1 = 1");
+        // The cn of the DN can be matched without search access
+        assert_eq!(compile(&conf, &PostgreSQL, "(cn=42)").0, "1 = 0");
+        assert_eq!(
+            compile(&conf, &PostgreSQL, "(cn:dn:=42)").0,
+            "LOWER(uid) = LOWER($1)"
+        );
+    }
+}
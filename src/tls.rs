@@ -0,0 +1,140 @@
+// Copyright (C) 2021  Joel Linn
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! TLS for connections of LDAP clients.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::ConfigServerTls;
+
+/// How often the certificate and key files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The server certificate, which can be replaced while the server is running.
+pub struct Tls {
+    resolver: Arc<CertResolver>,
+    acceptor: TlsAcceptor,
+}
+
+struct CertResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+impl Tls {
+    /// Loads the certificate and key.
+    pub fn load(conf: &ConfigServerTls) -> Result<Self, String> {
+        let resolver = Arc::new(CertResolver {
+            key: RwLock::new(load_certified_key(conf)?),
+        });
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        Ok(Self {
+            resolver,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.clone()
+    }
+
+    /// Replaces the certificate for new connections, established ones keep theirs.
+    pub fn reload(&self, conf: &ConfigServerTls) {
+        match load_certified_key(conf) {
+            Ok(key) => {
+                *self.resolver.key.write().unwrap() = key;
+                log::warn!("reloaded TLS certificate {}", conf.cert.display());
+            }
+            Err(err) => log::error!(
+                "Could not reload TLS certificate, keeping the old one: {}",
+                err
+            ),
+        }
+    }
+
+    /// Reloads the certificate after its files changed.
+    pub async fn watch(&self, conf: &ConfigServerTls) {
+        let modified = || {
+            [&conf.cert, &conf.key]
+                .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        };
+        let mut loaded: [Option<SystemTime>; 2] = modified();
+        let mut seen = loaded;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let current = modified();
+            // Wait until both files stopped changing, they are usually not replaced at once
+            if current != loaded && current == seen {
+                self.reload(conf);
+                loaded = current;
+            }
+            seen = current;
+        }
+    }
+}
+
+fn load_certified_key(conf: &ConfigServerTls) -> Result<Arc<CertifiedKey>, String> {
+    let certs = load_certs(&conf.cert)?;
+    let key = sign::any_supported_type(&load_key(&conf.key)?)
+        .map_err(|err| format!("Invalid TLS key {}: {}", conf.key.display(), err))?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Can not read {}: {}", path.display(), err))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, String> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .map_err(|err| format!("Can not parse certificates in {}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}", path.display()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, String> {
+    use rustls_pemfile::Item;
+    let mut reader = open(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|err| format!("Can not parse private key in {}: {}", path.display(), err))?
+        {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) | Some(Item::ECKey(key)) => {
+                return Ok(PrivateKey(key));
+            }
+            Some(_) => continue,
+            None => return Err(format!("No private key found in {}", path.display())),
+        }
+    }
+}